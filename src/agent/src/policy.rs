@@ -3,25 +3,29 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use anyhow::{bail, Result};
+mod measurement;
+mod policy_engine;
+
+use anyhow::{anyhow, bail, Result};
 use nix::sys::stat;
 use protobuf::MessageDyn;
-use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use slog::Drain;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use tokio::io::AsyncWriteExt;
-use tokio::time::{sleep, Duration};
 
 use crate::rpc::ttrpc_error;
 use crate::AGENT_POLICY;
 
-static EMPTY_JSON_INPUT: &str = "{\"input\":{}}";
+use policy_engine::opa::OpaEngine;
+use policy_engine::regorus::RegorusEngine;
+#[cfg(feature = "policy-wasm")]
+use policy_engine::wasm::WasmEngine;
+use policy_engine::{PolicyDecision, PolicyEngine, PolicyEngineKind, PolicyError};
 
-static OPA_DATA_PATH: &str = "/data";
-static OPA_POLICIES_PATH: &str = "/policies";
+static EMPTY_JSON_INPUT: &str = "{\"input\":{}}";
 
 static POLICY_LOG_FILE: &str = "/tmp/policy.txt";
 
@@ -33,12 +37,14 @@ macro_rules! sl {
 }
 
 async fn allow_request(policy: &mut AgentPolicy, ep: &str, request: &str) -> ttrpc::Result<()> {
-    if !policy.allow_request(ep, request).await {
-        warn!(sl!(), "{ep} is blocked by policy");
-        Err(ttrpc_error(
-            ttrpc::Code::PERMISSION_DENIED,
-            format!("{ep} is blocked by policy"),
-        ))
+    let decision = policy.allow_request(ep, request).await;
+    if !decision.allow {
+        let message = match &decision.reason {
+            Some(reason) => format!("{ep} is blocked by policy: {reason}"),
+            None => format!("{ep} is blocked by policy"),
+        };
+        warn!(sl!(), "{message}");
+        Err(ttrpc_error(ttrpc::Code::PERMISSION_DENIED, message))
     } else {
         Ok(())
     }
@@ -107,34 +113,30 @@ pub async fn do_set_policy(req: &protocols::agent::SetPolicyRequest) -> ttrpc::R
         .map_err(|e| ttrpc_error(ttrpc::Code::INVALID_ARGUMENT, e))
 }
 
-/// Example of HTTP response from OPA: {"result":true}
-#[derive(Debug, Serialize, Deserialize)]
-struct AllowResponse {
-    result: bool,
-}
-
 /// Singleton policy object.
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct AgentPolicy {
     /// When true policy errors are ignored, for debug purposes.
     allow_failures: bool,
 
-    /// OPA path used to query if an Agent gRPC request should be allowed.
-    /// The request name (e.g., CreateContainerRequest) must be added to
-    /// this path.
-    query_path: String,
-
-    /// OPA path used to add or delete a rego format Policy.
-    policy_path: String,
-
-    /// Client used to connect a single time to the OPA service and reused
-    /// for all the future communication with OPA.
-    opa_client: Option<reqwest::Client>,
+    /// Backend used to evaluate Agent Policy decisions. Boxed so that
+    /// `AgentPolicy` itself never depends on which engine was selected
+    /// at `initialize` time.
+    engine: Option<Box<dyn PolicyEngine>>,
 
     /// "/tmp/policy.txt" log file for policy activity.
     log_file: Option<tokio::fs::File>,
 }
 
+impl std::fmt::Debug for AgentPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AgentPolicy")
+            .field("allow_failures", &self.allow_failures)
+            .field("log_file", &self.log_file)
+            .finish()
+    }
+}
+
 impl AgentPolicy {
     /// Create AgentPolicy object.
     pub fn new() -> Self {
@@ -144,9 +146,15 @@ impl AgentPolicy {
         }
     }
 
-    /// Wait for OPA to start and connect to it.
+    /// Wait for the policy engine to start and connect to it.
+    ///
+    /// `engine_kind` selects the backend ([`PolicyEngineKind::Opa`] is its
+    /// `Default`). `launch_opa`/`opa_addr`/`policy_name` are only used by
+    /// the OPA backend; with any other backend they are ignored and no
+    /// `opa` process or HTTP client is ever created.
     pub async fn initialize(
         &mut self,
+        engine_kind: PolicyEngineKind,
         launch_opa: bool,
         opa_addr: &str,
         policy_name: &str,
@@ -164,159 +172,149 @@ impl AgentPolicy {
             debug!(sl!(), "policy: log file: {}", POLICY_LOG_FILE);
         }
 
-        if launch_opa {
-            start_opa(opa_addr)?;
-        }
-
-        let opa_uri = format!("http://{opa_addr}/v1");
-        self.query_path = format!("{opa_uri}{OPA_DATA_PATH}{policy_name}/");
-        self.policy_path = format!("{opa_uri}{OPA_POLICIES_PATH}{policy_name}");
-        let opa_client = reqwest::Client::builder().http1_only().build()?;
+        let mut engine: Box<dyn PolicyEngine> = match engine_kind {
+            PolicyEngineKind::Opa => Box::new(OpaEngine::new(launch_opa, opa_addr, policy_name)?),
+            PolicyEngineKind::Regorus => Box::new(RegorusEngine::new()),
+            #[cfg(feature = "policy-wasm")]
+            PolicyEngineKind::Wasm => Box::new(WasmEngine::new()),
+        };
+
+        // The WASM backend reads `default_policy` itself as a module file
+        // path; the other backends take its contents as Rego source text.
+        #[cfg(feature = "policy-wasm")]
+        let policy = if engine_kind == PolicyEngineKind::Wasm {
+            default_policy.to_string()
+        } else {
+            tokio::fs::read_to_string(default_policy).await?
+        };
+        #[cfg(not(feature = "policy-wasm"))]
         let policy = tokio::fs::read_to_string(default_policy).await?;
 
-        // This loop is necessary to get the opa_client connected to the
-        // OPA service while that service is starting. Future requests to
-        // OPA are expected to work without retrying, after connecting
-        // successfully for the first time.
-        for i in 0..50 {
-            if i > 0 {
-                sleep(Duration::from_millis(100)).await;
-                debug!(sl!(), "policy initialize: PUT failed, retrying");
-            }
+        engine.initialize(&policy).await.map_err(|e| anyhow!(e))?;
+        self.engine = Some(engine);
 
-            // Set-up the default policy.
-            if opa_client
-                .put(&self.policy_path)
-                .body(policy.clone())
-                .send()
-                .await
-                .is_ok()
-            {
-                self.opa_client = Some(opa_client);
-
-                // Check if requests causing policy errors should actually
-                // be allowed. That is an insecure configuration but is
-                // useful for allowing insecure pods to start, then connect to
-                // them and inspect Guest logs for the root cause of a failure.
-                //
-                // Note that post_query returns Ok(false) in case
-                // AllowRequestsFailingPolicy was not defined in the policy.
-                self.allow_failures = self
-                    .post_query("AllowRequestsFailingPolicy", EMPTY_JSON_INPUT)
-                    .await?;
-                return Ok(());
-            }
-        }
-        bail!("Failed to connect to OPA")
+        // Check if requests causing policy errors should actually
+        // be allowed. That is an insecure configuration but is
+        // useful for allowing insecure pods to start, then connect to
+        // them and inspect Guest logs for the root cause of a failure.
+        self.allow_failures = self.query_allow_failures().await?;
+
+        Ok(())
     }
 
-    /// Ask OPA to check if an API call should be allowed or not.
-    async fn allow_request(&mut self, ep: &str, request: &str) -> bool {
+    /// Ask the policy engine to check if an API call should be allowed or not.
+    async fn allow_request(&mut self, ep: &str, request: &str) -> PolicyDecision {
         let post_input = format!("{{\"input\":{request}}}");
-        self.log_opa_input(ep, &post_input).await;
-        match self.post_query(ep, &post_input).await {
+
+        let Some(engine) = &mut self.engine else {
+            let decision = PolicyDecision {
+                allow: false,
+                reason: Some("Agent Policy is not initialized".to_string()),
+            };
+            debug!(
+                sl!(),
+                "policy: failed to query endpoint {}: Agent Policy is not initialized. Returning false.",
+                ep
+            );
+            self.log_decision(ep, &post_input, &decision).await;
+            return decision;
+        };
+
+        let decision = match engine.allow_endpoint(ep, &post_input).await {
+            Ok(decision) if !decision.allow && self.allow_failures => {
+                match &decision.reason {
+                    Some(reason) => error!(sl!(), "policy: {} is denied: {}", ep, reason),
+                    None => error!(sl!(), "policy: {} is denied", ep),
+                }
+                warn!(
+                    sl!(),
+                    "policy: {} is denied, but AllowRequestsFailingPolicy is set. Ignoring.", ep
+                );
+                PolicyDecision {
+                    allow: true,
+                    reason: Some("denied by policy; AllowRequestsFailingPolicy is set".to_string()),
+                }
+            }
+            Ok(decision) => {
+                if !decision.allow {
+                    match &decision.reason {
+                        Some(reason) => error!(sl!(), "policy: {} is denied: {}", ep, reason),
+                        None => error!(sl!(), "policy: {} is denied", ep),
+                    }
+                }
+                decision
+            }
+            Err(PolicyError::UndefinedResult(_)) => {
+                if self.allow_failures {
+                    warn!(sl!(), "policy: {} has no matching rule. Ignoring error!", ep);
+                    PolicyDecision {
+                        allow: true,
+                        reason: Some("no matching rule; AllowRequestsFailingPolicy is set".to_string()),
+                    }
+                } else {
+                    warn!(sl!(), "policy: {} has no matching rule.", ep);
+                    PolicyDecision {
+                        allow: false,
+                        reason: Some("no matching rule".to_string()),
+                    }
+                }
+            }
             Err(e) => {
                 debug!(
                     sl!(),
                     "policy: failed to query endpoint {}: {:?}. Returning false.", ep, e
                 );
-                false
+                PolicyDecision {
+                    allow: false,
+                    reason: Some(e.to_string()),
+                }
             }
-            Ok(allowed) => allowed,
-        }
+        };
+
+        self.log_decision(ep, &post_input, &decision).await;
+        decision
     }
 
-    /// Replace the Policy in OPA.
+    /// Replace the Policy loaded in the policy engine.
     pub async fn set_policy(&mut self, policy: &str) -> Result<()> {
         check_policy_hash(policy)?;
 
-        if let Some(opa_client) = &mut self.opa_client {
-            // Delete the old rules.
-            opa_client.delete(&self.policy_path).send().await?;
-
-            // Put the new rules.
-            opa_client
-                .put(&self.policy_path)
-                .body(policy.to_string())
-                .send()
-                .await?;
-
-            // Check if requests causing policy errors should actually be allowed.
-            // That is an insecure configuration but is useful for allowing insecure
-            // pods to start, then connect to them and inspect Guest logs for the
-            // root cause of a failure.
-            //
-            // Note that post_query returns Ok(false) in case
-            // AllowRequestsFailingPolicy was not defined in the policy.
-            self.allow_failures = self
-                .post_query("AllowRequestsFailingPolicy", EMPTY_JSON_INPUT)
-                .await?;
-
-            Ok(())
-        } else {
+        let Some(engine) = &mut self.engine else {
             bail!("Agent Policy is not initialized")
-        }
-    }
-
-    // Post query to OPA.
-    async fn post_query(&mut self, ep: &str, post_input: &str) -> Result<bool> {
-        debug!(sl!(), "policy check: {ep}");
+        };
+        engine.set_policy(policy).await.map_err(|e| anyhow!(e))?;
 
-        if let Some(opa_client) = &mut self.opa_client {
-            let uri = format!("{}{ep}", &self.query_path);
-            let response = opa_client
-                .post(uri)
-                .body(post_input.to_string())
-                .send()
-                .await?;
+        // Check if requests causing policy errors should actually be allowed.
+        // That is an insecure configuration but is useful for allowing insecure
+        // pods to start, then connect to them and inspect Guest logs for the
+        // root cause of a failure.
+        self.allow_failures = self.query_allow_failures().await?;
 
-            if response.status() != http::StatusCode::OK {
-                bail!("policy: POST {} response status {}", ep, response.status());
-            }
+        Ok(())
+    }
 
-            let http_response = response.text().await?;
-            let opa_response: serde_json::Result<AllowResponse> =
-                serde_json::from_str(&http_response);
-
-            match opa_response {
-                Ok(resp) => {
-                    if !resp.result {
-                        if self.allow_failures {
-                            warn!(
-                                sl!(),
-                                "policy: POST {} response <{}>. Ignoring error!", ep, http_response
-                            );
-                            return Ok(true);
-                        } else {
-                            error!(sl!(), "policy: POST {} response <{}>", ep, http_response);
-                        }
-                    }
-                    Ok(resp.result)
-                }
-                Err(_) => {
-                    if self.allow_failures {
-                        warn!(
-                            sl!(),
-                            "policy: POST {} undefined response <{}>. Ignoring error!",
-                            ep,
-                            http_response
-                        );
-                        return Ok(true);
-                    } else {
-                        warn!(
-                            sl!(),
-                            "policy: POST {} undefined response <{}>.", ep, http_response
-                        );
-                    }
-                    Ok(false)
-                }
-            }
-        } else {
+    // Ask the policy engine whether AllowRequestsFailingPolicy is defined and
+    // set, treating an undefined rule the same way `allow_request` does
+    // before `allow_failures` itself is known: as "not allowed".
+    async fn query_allow_failures(&mut self) -> Result<bool> {
+        let Some(engine) = &mut self.engine else {
             bail!("Agent Policy is not initialized")
+        };
+
+        match engine
+            .allow_endpoint("AllowRequestsFailingPolicy", EMPTY_JSON_INPUT)
+            .await
+        {
+            Ok(decision) => Ok(decision.allow),
+            Err(PolicyError::UndefinedResult(_)) => Ok(false),
+            Err(e) => Err(anyhow!(e)),
         }
     }
 
-    async fn log_opa_input(&mut self, ep: &str, input: &str) {
+    /// Append a structured, one-JSON-object-per-line decision record to
+    /// `/tmp/policy.txt`, replacing the previous ad-hoc text format so
+    /// the log can be parsed for auditing.
+    async fn log_decision(&mut self, ep: &str, input: &str, decision: &PolicyDecision) {
         if let Some(log_file) = &mut self.log_file {
             match ep {
                 "StatsContainerRequest" | "ReadStreamRequest" | "SetPolicyRequest" => {
@@ -328,12 +326,36 @@ impl AgentPolicy {
                     //   The Policy text can be obtained directly from the pod YAML.
                 }
                 _ => {
-                    let log_entry = format!("[\"ep\":\"{ep}\",{input}],\n\n");
-
-                    if let Err(e) = log_file.write_all(log_entry.as_bytes()).await {
-                        warn!(sl!(), "policy: log_opa_input: write_all failed: {}", e);
-                    } else if let Err(e) = log_file.flush().await {
-                        warn!(sl!(), "policy: log_opa_input: flush failed: {}", e);
+                    let mut hasher = Sha256::new();
+                    hasher.update(input.as_bytes());
+                    let input_hash: String = hasher.finalize()[..8]
+                        .iter()
+                        .map(|b| format!("{b:02x}"))
+                        .collect();
+
+                    let timestamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+
+                    let entry = DecisionLogEntry {
+                        ep,
+                        timestamp,
+                        allow: decision.allow,
+                        reason: decision.reason.as_deref(),
+                        input_hash: &input_hash,
+                    };
+
+                    match serde_json::to_string(&entry) {
+                        Ok(log_line) => {
+                            let log_entry = format!("{log_line}\n");
+                            if let Err(e) = log_file.write_all(log_entry.as_bytes()).await {
+                                warn!(sl!(), "policy: log_decision: write_all failed: {}", e);
+                            } else if let Err(e) = log_file.flush().await {
+                                warn!(sl!(), "policy: log_decision: flush failed: {}", e);
+                            }
+                        }
+                        Err(e) => warn!(sl!(), "policy: log_decision: serialize failed: {}", e),
                     }
                 }
             }
@@ -341,25 +363,15 @@ impl AgentPolicy {
     }
 }
 
-fn start_opa(opa_addr: &str) -> Result<()> {
-    let bin_dirs = vec!["/bin", "/usr/bin", "/usr/local/bin"];
-    for bin_dir in &bin_dirs {
-        let opa_path = bin_dir.to_string() + "/opa";
-        if std::fs::metadata(&opa_path).is_ok() {
-            // args copied from kata-opa.service.in.
-            std::process::Command::new(&opa_path)
-                .arg("run")
-                .arg("--server")
-                .arg("--disable-telemetry")
-                .arg("--addr")
-                .arg(opa_addr)
-                .arg("--log-level")
-                .arg("info")
-                .spawn()?;
-            return Ok(());
-        }
-    }
-    bail!("OPA binary not found in {:?}", &bin_dirs);
+/// One machine-parseable decision record, written as a single JSON
+/// object per line to the policy log file.
+#[derive(serde::Serialize)]
+struct DecisionLogEntry<'a> {
+    ep: &'a str,
+    timestamp: u64,
+    allow: bool,
+    reason: Option<&'a str>,
+    input_hash: &'a str,
 }
 
 pub fn check_policy_hash(policy: &str) -> Result<()> {
@@ -368,15 +380,15 @@ pub fn check_policy_hash(policy: &str) -> Result<()> {
     let digest = hasher.finalize();
     debug!(sl!(), "policy: calculated hash ({:?})", digest.as_slice());
 
-    let mut firmware = sev::firmware::guest::Firmware::open()?;
-    let report_data: [u8; 64] = [0; 64];
-    let report = firmware.get_report(None, Some(report_data), Some(0))?;
+    let platform = measurement::detect_platform()?;
+    let bound_digest = platform.bound_digest()?;
 
-    if report.host_data != digest.as_slice() {
+    if bound_digest != digest.as_slice() {
         bail!(
-            "Unexpected policy hash ({:?}), expected ({:?})",
+            "Unexpected policy hash on {} platform: calculated ({:?}), attested ({:?})",
+            platform.name(),
             digest.as_slice(),
-            report.host_data
+            bound_digest
         );
     }
 
@@ -0,0 +1,120 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Per-platform TEE attestation lookups used by `check_policy_hash` to
+//! verify that the Sha256 of the injected policy matches the digest the
+//! hardware bound into its attestation report. Each confidential guest
+//! platform binds that digest into a different report field, so the
+//! comparison itself is shared while the field lookup is not.
+
+use anyhow::{anyhow, Result};
+
+/// A confidential-computing platform capable of reporting the digest it
+/// bound into its attestation report at launch time.
+pub trait PolicyMeasurement {
+    /// Human-readable platform name, used in error messages.
+    fn name(&self) -> &'static str;
+
+    /// The digest the hardware bound into its attestation report.
+    fn bound_digest(&self) -> Result<Vec<u8>>;
+}
+
+/// AMD SEV-SNP: the policy hash is bound into `report.host_data`.
+pub struct SevSnp;
+
+impl PolicyMeasurement for SevSnp {
+    fn name(&self) -> &'static str {
+        "SEV-SNP"
+    }
+
+    fn bound_digest(&self) -> Result<Vec<u8>> {
+        let mut firmware = sev::firmware::guest::Firmware::open()?;
+        let report_data: [u8; 64] = [0; 64];
+        let report = firmware.get_report(None, Some(report_data), Some(0))?;
+        Ok(report.host_data.to_vec())
+    }
+}
+
+/// Root of the configfs-tsm interface TDX guests use to request a TD
+/// report from the host.
+const TDX_CONFIGFS_TSM_REPORT: &str = "/sys/kernel/config/tsm/report";
+
+/// Byte offset and length of `MRCONFIGID` within the 1024-byte `TDREPORT`
+/// structure the TDX module returns, per the TDX module ABI:
+/// `REPORTMACSTRUCT` (256 bytes) + `TEE_TCB_INFO` (239 bytes) + reserved
+/// (17 bytes) bring `TDINFO_STRUCT` to offset 512; `MRCONFIGID` then
+/// follows `attributes`/`xfam`/`mrtd` (8 + 8 + 48 bytes) inside it.
+const TDREPORT_MRCONFIGID_OFFSET: usize = 512 + 8 + 8 + 48;
+const TDREPORT_MRCONFIGID_LEN: usize = 48;
+
+/// A per-request subdirectory under [`TDX_CONFIGFS_TSM_REPORT`], removed
+/// on drop so the kernel-side report it holds doesn't leak.
+struct TdReportRequest(std::path::PathBuf);
+
+impl Drop for TdReportRequest {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir(&self.0);
+    }
+}
+
+/// Intel TDX: the policy hash is bound into the TD report's
+/// `MRCONFIGID` field, fetched through the guest's configfs-tsm
+/// interface. That interface has no ready-made per-field attribute: a
+/// caller must `mkdir` a request subdirectory, then read back the
+/// `tdreport` attribute it exposes - the raw `TDREPORT` structure - and
+/// pick `MRCONFIGID` out of it at its defined offset.
+pub struct Tdx;
+
+impl PolicyMeasurement for Tdx {
+    fn name(&self) -> &'static str {
+        "TDX"
+    }
+
+    fn bound_digest(&self) -> Result<Vec<u8>> {
+        static REQUEST_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let id = REQUEST_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        let request_dir = std::path::Path::new(TDX_CONFIGFS_TSM_REPORT)
+            .join(format!("kata-agent-{}-{id}", std::process::id()));
+        std::fs::create_dir(&request_dir)
+            .map_err(|e| anyhow!("failed to create TDX report request {request_dir:?}: {e}"))?;
+        let request_dir = TdReportRequest(request_dir);
+
+        let tdreport_path = request_dir.0.join("tdreport");
+        let tdreport = std::fs::read(&tdreport_path)
+            .map_err(|e| anyhow!("failed to read TDREPORT from {tdreport_path:?}: {e}"))?;
+
+        if tdreport.len() < TDREPORT_MRCONFIGID_OFFSET + TDREPORT_MRCONFIGID_LEN {
+            return Err(anyhow!(
+                "TDREPORT from {:?} is only {} bytes, too short to contain MRCONFIGID",
+                tdreport_path,
+                tdreport.len()
+            ));
+        }
+
+        Ok(tdreport
+            [TDREPORT_MRCONFIGID_OFFSET..TDREPORT_MRCONFIGID_OFFSET + TDREPORT_MRCONFIGID_LEN]
+            .to_vec())
+    }
+}
+
+/// Detect which TEE platform this guest is running under.
+///
+/// SEV-SNP is probed first by trying to open its firmware device, since
+/// that is how `check_policy_hash` has always detected it; TDX is probed
+/// through the presence of its configfs-tsm report directory.
+pub fn detect_platform() -> Result<Box<dyn PolicyMeasurement>> {
+    if sev::firmware::guest::Firmware::open().is_ok() {
+        return Ok(Box::new(SevSnp));
+    }
+
+    if std::path::Path::new(TDX_CONFIGFS_TSM_REPORT).exists() {
+        return Ok(Box::new(Tdx));
+    }
+
+    Err(anyhow!(
+        "no supported TEE attestation interface detected (tried SEV-SNP, TDX)"
+    ))
+}
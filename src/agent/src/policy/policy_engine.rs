@@ -0,0 +1,131 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Pluggable backends for evaluating Agent Policy decisions.
+//!
+//! `AgentPolicy` (in the parent `policy` module) only depends on the
+//! [`PolicyEngine`] trait, never on a specific decision backend. This
+//! lets the OPA HTTP backend below be swapped for an in-process
+//! evaluator, a compiled WASM module, or anything else, without touching
+//! the ttrpc call sites in `policy.rs`.
+
+pub mod opa;
+pub mod regorus;
+#[cfg(feature = "policy-wasm")]
+pub mod wasm;
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+/// Convenience macro to obtain the scope logger, shared with the
+/// backends in this module's submodules.
+macro_rules! sl {
+    () => {
+        slog_scope::logger()
+    };
+}
+pub(crate) use sl;
+
+/// Errors returned by a [`PolicyEngine`] implementation.
+///
+/// These are engine-agnostic: callers only need to know whether
+/// initialization, policy replacement, or a query failed - not which
+/// backend produced the failure.
+#[derive(Debug, Error)]
+pub enum PolicyError {
+    #[error("policy: failed to initialize engine: {0}")]
+    InitFailed(String),
+
+    #[error("policy: failed to set policy: {0}")]
+    SetPolicyFailed(String),
+
+    #[error("policy: query failed: {0}")]
+    QueryFailed(String),
+
+    #[error("policy: endpoint {0} has an undefined result")]
+    UndefinedResult(String),
+}
+
+/// The outcome of evaluating a single endpoint.
+///
+/// `reason` is populated whenever the engine's decision document included
+/// one (e.g. `{"allow": false, "reason": "..."}`); a bare boolean decision
+/// leaves it `None`.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyDecision {
+    pub allow: bool,
+    pub reason: Option<String>,
+}
+
+/// A backend capable of evaluating Agent Policy decisions.
+///
+/// Implementations are free to evaluate Rego locally, call out to an
+/// external service, or run a compiled policy module. Only `Send` is
+/// required, not `Sync`: backends such as the in-process `regorus`
+/// evaluator are not thread-safe and rely on the existing `AGENT_POLICY`
+/// mutex to serialize all access.
+#[async_trait]
+pub trait PolicyEngine: Send {
+    /// Load the initial policy document.
+    async fn initialize(&mut self, policy: &str) -> Result<(), PolicyError>;
+
+    /// Replace the currently loaded policy document.
+    async fn set_policy(&mut self, policy: &str) -> Result<(), PolicyError>;
+
+    /// Evaluate whether `ep` is allowed for the given `input_json`.
+    async fn allow_endpoint(
+        &mut self,
+        ep: &str,
+        input_json: &str,
+    ) -> Result<PolicyDecision, PolicyError>;
+}
+
+/// Interpret a decision document that may be a bare boolean (the
+/// long-standing `{"result": bool}` shape) or a richer
+/// `{"allow": bool, "reason": string}` object, as produced by either an
+/// OPA HTTP response's `result` field or an embedded engine's query
+/// result.
+pub(crate) fn decision_from_json(
+    ep: &str,
+    value: &serde_json::Value,
+) -> Result<PolicyDecision, PolicyError> {
+    if let Some(allow) = value.as_bool() {
+        return Ok(PolicyDecision {
+            allow,
+            reason: None,
+        });
+    }
+
+    if let Some(allow) = value.get("allow").and_then(serde_json::Value::as_bool) {
+        let reason = value
+            .get("reason")
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string);
+        return Ok(PolicyDecision { allow, reason });
+    }
+
+    Err(PolicyError::UndefinedResult(ep.to_string()))
+}
+
+/// Which [`PolicyEngine`] backend `AgentPolicy::initialize` should construct.
+///
+/// Defaults to [`PolicyEngineKind::Opa`] so that guests which don't pass
+/// an explicit choice keep talking to an external OPA server exactly as
+/// before.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PolicyEngineKind {
+    /// External OPA server reached over HTTP (the original backend).
+    #[default]
+    Opa,
+
+    /// In-process Rego evaluation via `regorus`, avoiding the external
+    /// `opa` process and the HTTP round-trip on every guarded request.
+    Regorus,
+
+    /// Precompiled WebAssembly policy module evaluated with `wasmtime`.
+    /// Only available when the `policy-wasm` cargo feature is enabled.
+    #[cfg(feature = "policy-wasm")]
+    Wasm,
+}
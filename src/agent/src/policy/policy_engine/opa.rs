@@ -0,0 +1,178 @@
+// Copyright (c) 2023 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The original `PolicyEngine` backend: an external OPA server reached
+//! over its HTTP API.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tokio::time::{sleep, Duration};
+
+use super::sl;
+use super::{decision_from_json, PolicyDecision, PolicyEngine, PolicyError};
+
+static OPA_DATA_PATH: &str = "/data";
+static OPA_POLICIES_PATH: &str = "/policies";
+
+/// [`PolicyEngine`] backed by an external OPA server reached over HTTP.
+#[derive(Debug, Default)]
+pub struct OpaEngine {
+    /// OPA path used to query if an Agent gRPC request should be allowed.
+    /// The request name (e.g., CreateContainerRequest) must be added to
+    /// this path.
+    query_path: String,
+
+    /// OPA path used to add or delete a rego format Policy.
+    policy_path: String,
+
+    /// Client used to connect a single time to the OPA service and reused
+    /// for all the future communication with OPA.
+    opa_client: Option<reqwest::Client>,
+}
+
+impl OpaEngine {
+    /// Create an engine targeting `opa_addr`, launching the `opa` binary
+    /// first when `launch_opa` is set.
+    pub fn new(launch_opa: bool, opa_addr: &str, policy_name: &str) -> Result<Self> {
+        if launch_opa {
+            start_opa(opa_addr)?;
+        }
+
+        let opa_uri = format!("http://{opa_addr}/v1");
+        Ok(Self {
+            query_path: format!("{opa_uri}{OPA_DATA_PATH}{policy_name}/"),
+            policy_path: format!("{opa_uri}{OPA_POLICIES_PATH}{policy_name}"),
+            opa_client: None,
+        })
+    }
+}
+
+#[async_trait]
+impl PolicyEngine for OpaEngine {
+    /// Wait for OPA to start and connect to it.
+    async fn initialize(&mut self, policy: &str) -> Result<(), PolicyError> {
+        let opa_client = reqwest::Client::builder()
+            .http1_only()
+            .build()
+            .map_err(|e| PolicyError::InitFailed(e.to_string()))?;
+
+        // This loop is necessary to get the opa_client connected to the
+        // OPA service while that service is starting. Future requests to
+        // OPA are expected to work without retrying, after connecting
+        // successfully for the first time.
+        for i in 0..50 {
+            if i > 0 {
+                sleep(Duration::from_millis(100)).await;
+                debug!(sl!(), "policy initialize: PUT failed, retrying");
+            }
+
+            if opa_client
+                .put(&self.policy_path)
+                .body(policy.to_string())
+                .send()
+                .await
+                .is_ok()
+            {
+                self.opa_client = Some(opa_client);
+                return Ok(());
+            }
+        }
+
+        Err(PolicyError::InitFailed("Failed to connect to OPA".to_string()))
+    }
+
+    /// Replace the Policy in OPA.
+    async fn set_policy(&mut self, policy: &str) -> Result<(), PolicyError> {
+        let opa_client = self.opa_client.as_mut().ok_or_else(|| {
+            PolicyError::SetPolicyFailed("Agent Policy is not initialized".to_string())
+        })?;
+
+        // Delete the old rules.
+        opa_client
+            .delete(&self.policy_path)
+            .send()
+            .await
+            .map_err(|e| PolicyError::SetPolicyFailed(e.to_string()))?;
+
+        // Put the new rules.
+        opa_client
+            .put(&self.policy_path)
+            .body(policy.to_string())
+            .send()
+            .await
+            .map_err(|e| PolicyError::SetPolicyFailed(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Post query to OPA.
+    ///
+    /// The response's `result` field is either a bare boolean (the
+    /// long-standing shape) or `{"allow": bool, "reason": string}`.
+    async fn allow_endpoint(
+        &mut self,
+        ep: &str,
+        input_json: &str,
+    ) -> Result<PolicyDecision, PolicyError> {
+        debug!(sl!(), "policy check: {ep}");
+
+        let opa_client = self
+            .opa_client
+            .as_mut()
+            .ok_or_else(|| PolicyError::QueryFailed("Agent Policy is not initialized".to_string()))?;
+
+        let uri = format!("{}{ep}", &self.query_path);
+        let response = opa_client
+            .post(uri)
+            .body(input_json.to_string())
+            .send()
+            .await
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        if response.status() != http::StatusCode::OK {
+            return Err(PolicyError::QueryFailed(format!(
+                "POST {ep} response status {}",
+                response.status()
+            )));
+        }
+
+        let http_response = response
+            .text()
+            .await
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        let parsed: serde_json::Value = serde_json::from_str(&http_response).map_err(|_| {
+            debug!(sl!(), "policy: POST {ep} undefined response <{http_response}>.");
+            PolicyError::UndefinedResult(ep.to_string())
+        })?;
+
+        let result = parsed
+            .get("result")
+            .ok_or_else(|| PolicyError::UndefinedResult(ep.to_string()))?;
+
+        decision_from_json(ep, result)
+    }
+}
+
+fn start_opa(opa_addr: &str) -> Result<()> {
+    let bin_dirs = vec!["/bin", "/usr/bin", "/usr/local/bin"];
+    for bin_dir in &bin_dirs {
+        let opa_path = bin_dir.to_string() + "/opa";
+        if std::fs::metadata(&opa_path).is_ok() {
+            // args copied from kata-opa.service.in.
+            std::process::Command::new(&opa_path)
+                .arg("run")
+                .arg("--server")
+                .arg("--disable-telemetry")
+                .arg("--addr")
+                .arg(opa_addr)
+                .arg("--log-level")
+                .arg("info")
+                .spawn()?;
+            return Ok(());
+        }
+    }
+    bail!("OPA binary not found in {:?}", &bin_dirs);
+}
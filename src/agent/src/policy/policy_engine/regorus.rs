@@ -0,0 +1,95 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! In-process Rego evaluation via `regorus`, so guarded requests are
+//! decided without spawning an external `opa` process or doing an HTTP
+//! round-trip for every request.
+
+use async_trait::async_trait;
+
+use super::sl;
+use super::{decision_from_json, PolicyDecision, PolicyEngine, PolicyError};
+
+const POLICY_MODULE_NAME: &str = "agent_policy.rego";
+const POLICY_PACKAGE: &str = "data.agent_policy";
+
+/// [`PolicyEngine`] backed by an in-process `regorus::Engine`.
+///
+/// `regorus::Engine` is not `Sync`, so this backend must only be reached
+/// through the `AGENT_POLICY` mutex - the same serialization the OPA HTTP
+/// client already relies on.
+pub struct RegorusEngine {
+    engine: regorus::Engine,
+}
+
+impl Default for RegorusEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegorusEngine {
+    pub fn new() -> Self {
+        Self {
+            engine: regorus::Engine::new(),
+        }
+    }
+
+    fn load_policy(&mut self, policy: &str) -> Result<(), PolicyError> {
+        self.engine
+            .add_policy(POLICY_MODULE_NAME.to_string(), policy.to_string())
+            .map_err(|e| PolicyError::InitFailed(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PolicyEngine for RegorusEngine {
+    async fn initialize(&mut self, policy: &str) -> Result<(), PolicyError> {
+        self.load_policy(policy)
+    }
+
+    async fn set_policy(&mut self, policy: &str) -> Result<(), PolicyError> {
+        // regorus has no API to drop a single named module, so start from
+        // a fresh engine before loading the replacement - mirroring the
+        // OPA backend's "delete the old rules, then put the new ones".
+        self.engine = regorus::Engine::new();
+        self.load_policy(policy).map_err(|e| match e {
+            PolicyError::InitFailed(msg) => PolicyError::SetPolicyFailed(msg),
+            e => e,
+        })
+    }
+
+    /// The rule at `data.agent_policy.<ep>` may evaluate to a bare
+    /// boolean or a `{"allow": bool, "reason": string}` object; both are
+    /// accepted, mirroring the OPA HTTP backend's decision document.
+    async fn allow_endpoint(
+        &mut self,
+        ep: &str,
+        input_json: &str,
+    ) -> Result<PolicyDecision, PolicyError> {
+        debug!(sl!(), "policy check: {ep}");
+
+        let input = regorus::Value::from_json_str(input_json)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+        self.engine.set_input(input);
+
+        let query = format!("{POLICY_PACKAGE}.{ep}");
+        let result = self
+            .engine
+            .eval_query(query, false)
+            .map_err(|e| PolicyError::UndefinedResult(format!("{ep}: {e}")))?;
+
+        let value = result
+            .result
+            .first()
+            .and_then(|r| r.expressions.first())
+            .map(|e| &e.value)
+            .ok_or_else(|| PolicyError::UndefinedResult(ep.to_string()))?;
+
+        let json = serde_json::to_value(value).map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+        decision_from_json(ep, &json)
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! WASM policy-module backend: loads a precompiled WebAssembly policy
+//! module (e.g. produced by `opa build -t wasm`) and evaluates Agent
+//! Policy decisions against it with `wasmtime`, reusing the OPA
+//! `opa_eval`/memory-allocation ABI instead of talking to an external
+//! `opa` server.
+//!
+//! Gated behind the `policy-wasm` cargo feature since `wasmtime` pulls in
+//! a full WASM runtime that most deployments don't need.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use wasmtime::{Instance, Memory, Module, Store, TypedFunc};
+
+use super::sl;
+use super::{decision_from_json, PolicyDecision, PolicyEngine, PolicyError};
+
+/// Name of the linear memory exported by `opa build -t wasm` modules.
+const WASM_MEMORY_EXPORT: &str = "memory";
+
+fn init_failed(e: impl std::fmt::Display) -> PolicyError {
+    PolicyError::InitFailed(e.to_string())
+}
+
+/// Marker genpolicy appends the `policy_data` JSON document after, in the
+/// combined Rego-plus-data text it sends to every policy engine backend.
+const POLICY_DATA_MARKER: &str = "policy_data := ";
+
+/// Pull the `policy_data` JSON document out of the combined Rego-plus-data
+/// text genpolicy sends, since the WASM module (already compiled with the
+/// Rego rules) only wants the data half.
+fn extract_policy_data(policy: &str) -> Result<&str, PolicyError> {
+    let idx = policy.rfind(POLICY_DATA_MARKER).ok_or_else(|| {
+        PolicyError::SetPolicyFailed("policy text is missing a policy_data assignment".to_string())
+    })?;
+    Ok(policy[idx + POLICY_DATA_MARKER.len()..].trim())
+}
+
+/// [`PolicyEngine`] backed by a compiled WASM policy module, using the
+/// exported ABI that `opa build -t wasm` produces:
+/// `opa_malloc`, `opa_json_parse`, `opa_json_dump`, `opa_eval_ctx_new`,
+/// `opa_eval_ctx_set_input`, `opa_eval_ctx_set_data`,
+/// `opa_eval_ctx_get_result` and `eval`.
+pub struct WasmEngine {
+    engine: wasmtime::Engine,
+    store: Store<()>,
+    instance: Option<Instance>,
+    memory: Option<Memory>,
+    /// Address of the parsed `policy_data` document, set by `set_policy`
+    /// and attached to every evaluation context.
+    data_addr: Option<i32>,
+    /// Maps each compiled entrypoint's slash-separated Rego path (e.g.
+    /// `agent_policy/CreateContainerRequest`) to the numeric id
+    /// `opa_eval_ctx_set_entrypoint` expects, read once from the module's
+    /// `entrypoints` export. Empty for modules built with a single,
+    /// default entrypoint, which don't export that function.
+    entrypoints: HashMap<String, i32>,
+}
+
+impl Default for WasmEngine {
+    fn default() -> Self {
+        let engine = wasmtime::Engine::default();
+        let store = Store::new(&engine, ());
+        Self {
+            engine,
+            store,
+            instance: None,
+            memory: None,
+            data_addr: None,
+            entrypoints: HashMap::new(),
+        }
+    }
+}
+
+impl WasmEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn instance(&self) -> Result<Instance, PolicyError> {
+        self.instance
+            .ok_or_else(|| PolicyError::QueryFailed("WASM module is not loaded".to_string()))
+    }
+
+    fn memory(&self) -> Result<Memory, PolicyError> {
+        self.memory
+            .ok_or_else(|| PolicyError::QueryFailed("WASM module is not loaded".to_string()))
+    }
+
+    fn typed_func<Params, Results>(
+        &mut self,
+        instance: &Instance,
+        name: &str,
+    ) -> Result<TypedFunc<Params, Results>, PolicyError>
+    where
+        Params: wasmtime::WasmParams,
+        Results: wasmtime::WasmResults,
+    {
+        instance
+            .get_typed_func(&mut self.store, name)
+            .map_err(|e| PolicyError::QueryFailed(format!("missing export {name}: {e}")))
+    }
+
+    /// Write `json` into the module's linear memory via `opa_malloc` +
+    /// `opa_json_parse`, returning the address of the parsed value.
+    fn write_json(&mut self, instance: &Instance, json: &str) -> Result<i32, PolicyError> {
+        let memory = self.memory()?;
+        let malloc: TypedFunc<i32, i32> = self.typed_func(instance, "opa_malloc")?;
+        let parse: TypedFunc<(i32, i32), i32> = self.typed_func(instance, "opa_json_parse")?;
+
+        let bytes = json.as_bytes();
+        let addr = malloc
+            .call(&mut self.store, bytes.len() as i32)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+        memory
+            .write(&mut self.store, addr as usize, bytes)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        parse
+            .call(&mut self.store, (addr, bytes.len() as i32))
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))
+    }
+
+    /// Read back the value at `addr` as a JSON string via `opa_json_dump`.
+    fn read_json(&mut self, instance: &Instance, addr: i32) -> Result<String, PolicyError> {
+        let memory = self.memory()?;
+        let dump: TypedFunc<i32, i32> = self.typed_func(instance, "opa_json_dump")?;
+
+        let str_addr = dump
+            .call(&mut self.store, addr)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        let data = memory.data(&self.store);
+        let bytes = &data[str_addr as usize..];
+        let end = bytes
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| PolicyError::QueryFailed("unterminated WASM result string".to_string()))?;
+
+        String::from_utf8(bytes[..end].to_vec())
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))
+    }
+
+    /// Read the module's `entrypoints` export, if present, mapping each
+    /// compiled entrypoint's slash-separated Rego path to the numeric id
+    /// `opa_eval_ctx_set_entrypoint` expects. Modules built without `-e`
+    /// flags (a single, default entrypoint) don't export this function;
+    /// an empty map is returned for those, and `allow_endpoint` then
+    /// leaves every evaluation on the context's default entrypoint.
+    fn load_entrypoints(&mut self, instance: &Instance) -> HashMap<String, i32> {
+        let Ok(entrypoints_fn) = self.typed_func::<(), i32>(instance, "entrypoints") else {
+            return HashMap::new();
+        };
+
+        let parsed: Result<HashMap<String, i32>, PolicyError> = (|| {
+            let addr = entrypoints_fn
+                .call(&mut self.store, ())
+                .map_err(|e| PolicyError::InitFailed(e.to_string()))?;
+            let json = self.read_json(instance, addr)?;
+            let value: serde_json::Value = serde_json::from_str(&json)
+                .map_err(|e| PolicyError::InitFailed(e.to_string()))?;
+            let entries = value.as_object().ok_or_else(|| {
+                PolicyError::InitFailed("entrypoints export did not return an object".to_string())
+            })?;
+            Ok(entries
+                .iter()
+                .filter_map(|(name, id)| id.as_i64().map(|id| (name.clone(), id as i32)))
+                .collect())
+        })();
+
+        parsed.unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl PolicyEngine for WasmEngine {
+    /// `policy` is a filesystem path to the precompiled `.wasm` module
+    /// produced by `opa build -t wasm` (or an equivalent WASI-ABI build),
+    /// mirroring how `default_policy` already names a file for the other
+    /// backends.
+    async fn initialize(&mut self, policy: &str) -> Result<(), PolicyError> {
+        let bytes = std::fs::read(policy).map_err(init_failed)?;
+        let module = Module::new(&self.engine, &bytes).map_err(init_failed)?;
+        let instance =
+            Instance::new(&mut self.store, &module, &[]).map_err(init_failed)?;
+        let memory = instance
+            .get_memory(&mut self.store, WASM_MEMORY_EXPORT)
+            .ok_or_else(|| init_failed("module does not export linear memory"))?;
+
+        self.instance = Some(instance);
+        self.memory = Some(memory);
+        self.entrypoints = self.load_entrypoints(&instance);
+
+        debug!(sl!(), "policy: loaded WASM policy module {}", policy);
+        Ok(())
+    }
+
+    /// `policy` is the Rego source text genpolicy sends every backend,
+    /// with a trailing `policy_data := { ... }` assignment appended to it
+    /// (see `genpolicy`'s `replica_set.rs`). The WASM module is already
+    /// compiled with the Rego rules baked in, so only the `policy_data`
+    /// JSON document needs to be extracted and loaded into the module's
+    /// data section.
+    async fn set_policy(&mut self, policy: &str) -> Result<(), PolicyError> {
+        let policy_data = extract_policy_data(policy)?;
+
+        let instance = self.instance()?;
+        let addr = self.write_json(&instance, policy_data).map_err(|e| match e {
+            PolicyError::QueryFailed(msg) => PolicyError::SetPolicyFailed(msg),
+            e => e,
+        })?;
+        self.data_addr = Some(addr);
+        Ok(())
+    }
+
+    async fn allow_endpoint(
+        &mut self,
+        ep: &str,
+        input_json: &str,
+    ) -> Result<PolicyDecision, PolicyError> {
+        debug!(sl!(), "policy check: {ep}");
+
+        let instance = self.instance()?;
+        let input_addr = self.write_json(&instance, input_json)?;
+
+        let ctx_new: TypedFunc<(), i32> = self.typed_func(&instance, "opa_eval_ctx_new")?;
+        let ctx = ctx_new
+            .call(&mut self.store, ())
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        let set_input: TypedFunc<(i32, i32), ()> =
+            self.typed_func(&instance, "opa_eval_ctx_set_input")?;
+        set_input
+            .call(&mut self.store, (ctx, input_addr))
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        if let Some(data_addr) = self.data_addr {
+            let set_data: TypedFunc<(i32, i32), ()> =
+                self.typed_func(&instance, "opa_eval_ctx_set_data")?;
+            set_data
+                .call(&mut self.store, (ctx, data_addr))
+                .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+        }
+
+        // Modules compiled with more than one entrypoint default to
+        // evaluating entrypoint 0 unless told otherwise; resolve `ep` to
+        // its numeric id so each Agent endpoint runs its own Rego rule
+        // instead of always evaluating the first one.
+        let entrypoint_name = format!("agent_policy/{ep}");
+        if let Some(&entrypoint_id) = self
+            .entrypoints
+            .get(&entrypoint_name)
+            .or_else(|| self.entrypoints.get(ep))
+        {
+            let set_entrypoint: TypedFunc<(i32, i32), ()> =
+                self.typed_func(&instance, "opa_eval_ctx_set_entrypoint")?;
+            set_entrypoint
+                .call(&mut self.store, (ctx, entrypoint_id))
+                .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+        } else if !self.entrypoints.is_empty() {
+            return Err(PolicyError::QueryFailed(format!(
+                "{ep}: no matching WASM entrypoint (module exports {:?})",
+                self.entrypoints.keys().collect::<Vec<_>>()
+            )));
+        }
+
+        let eval: TypedFunc<i32, i32> = self.typed_func(&instance, "eval")?;
+        let rc = eval
+            .call(&mut self.store, ctx)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+        if rc != 0 {
+            return Err(PolicyError::QueryFailed(format!(
+                "{ep}: WASM eval returned error code {rc}"
+            )));
+        }
+
+        let get_result: TypedFunc<i32, i32> =
+            self.typed_func(&instance, "opa_eval_ctx_get_result")?;
+        let result_addr = get_result
+            .call(&mut self.store, ctx)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        // `opa_eval_ctx_get_result` yields `[{"result": <value>}]`; the
+        // first (and only) entrypoint's result is what we asked for.
+        let result_json = self.read_json(&instance, result_addr)?;
+        let results: serde_json::Value = serde_json::from_str(&result_json)
+            .map_err(|e| PolicyError::QueryFailed(e.to_string()))?;
+
+        let decision = results
+            .get(0)
+            .and_then(|entry| entry.get("result"))
+            .ok_or_else(|| PolicyError::UndefinedResult(ep.to_string()))?;
+
+        decision_from_json(ep, decision)
+    }
+}
@@ -5,27 +5,86 @@
 
 // Allow Docker image config field names.
 #![allow(non_snake_case)]
+mod auth;
+mod containerd_source;
+mod distribution_source;
+mod image_source;
+mod layer_cache;
+
 use crate::policy;
 use crate::verity;
 
+pub use image_source::{ImageSourceKind, TargetPlatform};
+pub use layer_cache::CacheBudget;
+
+use containerd_source::ContainerdSource;
+use distribution_source::DistributionSource;
+use image_source::ImageSource;
+
 use anyhow::{anyhow, Result};
-use containerd_client::services::v1::GetImageRequest;
-use containerd_client::with_namespace;
-use log::warn;
-use log::{debug, info};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use sha2::{digest::typenum::Unsigned, digest::OutputSizeUser, Sha256};
-use std::{io::Seek, io::Write, path::Path};
+use std::{collections::HashMap, io::Seek, io::Write, path::Path, sync::Arc};
 use tokio::fs;
-use k8s_cri::v1::image_service_client::ImageServiceClient;
-use std::collections::HashMap;
-use std::convert::TryFrom;
-use tokio::net::UnixStream;
-use tonic::transport::{Endpoint, Uri};
-use tower::service_fn;
-use tonic::Request;
-use tokio::io;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Default number of layers to download and hash concurrently, used when
+/// a caller doesn't pick an explicit concurrency (see [`PullOptions`]).
+const LAYER_CONCURRENCY: usize = 4;
+
+/// Per-digest locks so that two concurrently-downloaded layers sharing the
+/// same digest (a manifest can legitimately repeat one) never race on the
+/// same `layers_cache` file names.
+type DigestLocks = Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>;
+
+/// Get (creating if necessary) the lock guarding `digest`'s cache files.
+async fn digest_lock(locks: &DigestLocks, digest: &str) -> Arc<Mutex<()>> {
+    locks
+        .lock()
+        .await
+        .entry(digest.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(())))
+        .clone()
+}
+
+/// Digests whose per-digest lock is currently held, i.e. some task (this
+/// one included) is still downloading, decompressing or hashing them, so
+/// [`layer_cache::LayerCache::enforce_budget`] can leave their cache files
+/// alone rather than evicting them out from under that task.
+async fn in_flight_digests(locks: &DigestLocks) -> std::collections::HashSet<String> {
+    locks
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, lock)| lock.try_lock().is_err())
+        .map(|(digest, _)| digest.clone())
+        .collect()
+}
+
+/// How a layer blob is compressed, determining which decoder
+/// [`create_decompressed_layer_file`] must run before the tarfs-index and
+/// verity steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LayerCompression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+/// Map a layer's `mediaType` to the compression used for its blob,
+/// erroring out on any type this application doesn't know how to
+/// decompress rather than silently skipping the layer.
+fn layer_compression(media_type: &str) -> Result<LayerCompression> {
+    match media_type {
+        "application/vnd.docker.image.rootfs.diff.tar.gzip"
+        | "application/vnd.oci.image.layer.v1.tar+gzip" => Ok(LayerCompression::Gzip),
+        "application/vnd.oci.image.layer.v1.tar+zstd" => Ok(LayerCompression::Zstd),
+        "application/vnd.oci.image.layer.v1.tar" => Ok(LayerCompression::None),
+        other => Err(anyhow!("Unsupported layer media type: {other}")),
+    }
+}
 
 /// Container image properties obtained from an OCI repository.
 #[derive(Clone, Debug, Default)]
@@ -67,27 +126,57 @@ pub struct ImageLayer {
     pub verity_hash: String,
 }
 
-const CONTAINERD_SOCKET_PATH : &str = "npipe:////./pipe/containerd-containerd";
-
 impl Container {
     pub async fn new(use_cached_files: bool, image: &str) -> Result<Self> {
+        Self::new_from_source(
+            ImageSourceKind::default(),
+            use_cached_files,
+            CacheBudget::default(),
+            TargetPlatform::default(),
+            LAYER_CONCURRENCY,
+            image,
+        )
+        .await
+    }
+
+    /// Like [`Container::new`], but lets the caller pick which
+    /// [`ImageSource`] backend resolves and fetches `image`, what budget
+    /// bounds the on-disk `layers_cache`, which platform to select out of
+    /// a multi-arch image index, and how many layers to download and hash
+    /// concurrently.
+    pub async fn new_from_source(
+        source_kind: ImageSourceKind,
+        use_cached_files: bool,
+        cache_budget: CacheBudget,
+        target_platform: TargetPlatform,
+        concurrency: usize,
+        image: &str,
+    ) -> Result<Self> {
         info!("============================================");
         info!("Pulling image and layers for {:?}", image);
 
-        let client = containerd_client::Client::from_path(CONTAINERD_SOCKET_PATH).await?;
-        pull_image(image).await?;
-        let manifest = get_image_manifest(image, &client).await?;
-        let config_layer = get_config_layer(image).await.unwrap();          
+        let source: Arc<dyn ImageSource> = match source_kind {
+            ImageSourceKind::Containerd => Arc::new(ContainerdSource::new().await?),
+            ImageSourceKind::Distribution => Arc::new(DistributionSource::new()?),
+        };
+
+        source.pull_image(image).await?;
+        let manifest = source.image_manifest(image, &target_platform).await?;
+        let config_layer = source.config_layer(image, &manifest).await?;
         let image_layers = get_image_layers(
             use_cached_files,
+            &cache_budget,
+            concurrency,
+            image,
             &manifest,
             &config_layer,
-            &client
-        ).await?;
+            source,
+        )
+        .await?;
 
         Ok(Container {
             config_layer,
-            image_layers
+            image_layers,
         })
     }
 
@@ -190,151 +279,86 @@ impl Container {
     }
 }
 
-async fn get_config_layer(image_ref: &str) ->  Result<DockerConfigLayer>{
-    
-    let channel = Endpoint::try_from("http://[::]")
-        .unwrap()
-        .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(CONTAINERD_SOCKET_PATH)))
-        .await?;
-
-    let mut client = ImageServiceClient::new(channel);
-
-    let req =   k8s_cri::v1::ImageStatusRequest {
-        image: Some(k8s_cri::v1::ImageSpec {
-            image: image_ref.to_string(),
-            annotations: HashMap::new(),
-        }),
-        verbose: true
-    };
-
-    let resp = client.image_status(req).await?;
-    let image_layers = resp.into_inner();
-
-    let status_info: serde_json::Value = serde_json::from_str(image_layers.info.get("info").unwrap())?;
-    let image_spec = status_info["imageSpec"].as_object().unwrap();
-    let docker_config_layer: DockerConfigLayer = serde_json::from_value(serde_json::to_value(image_spec)?)?;
-
-    Ok(docker_config_layer)
-}
-
-pub async fn pull_image(image_ref: &str) ->  Result<()>{
-    let channel = Endpoint::try_from("http://[::]")
-        .unwrap()
-        .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(CONTAINERD_SOCKET_PATH)))
-        .await?;
-
-    let mut client = ImageServiceClient::new(channel);
-
-    let req =   k8s_cri::v1::PullImageRequest {
-        image: Some(k8s_cri::v1::ImageSpec {
-            image: image_ref.to_string(),
-            annotations: HashMap::new(),
-        }),
-        auth: None,
-        sandbox_config: None,
-    };
-
-    client.pull_image(req).await?;
-
-    Ok(())
-}
-
-async fn get_content (digest: &str, client: &containerd_client::Client) ->  Result<serde_json::Value, anyhow::Error>{
-
-    let req = containerd_client::services::v1::ReadContentRequest {
-        digest: digest.to_string(),
-        offset: 0,
-        size: 0,
-    };
-    let req = with_namespace!(req, "k8s.io");
-    let mut c = client.content();
-    let resp = c.read(req).await?;
-    let mut stream = resp.into_inner();
-
-    while let Some(chunk) = stream.message().await? {
-        if chunk.offset < 0 {
-            return Err(anyhow!("Negative offset in chunk"));
-        }
-        else {
-            return Ok(serde_json::from_slice(&chunk.data)?);
-        }
-    }
-
-    Err(anyhow!("Unable to find content for digest: {}", digest))
-}
-
-async fn get_image_manifest (image_ref: &str, client: &containerd_client::Client) ->  Result<serde_json::Value>{
-
-    let mut imageChannel = client.images();
-
-    let req = GetImageRequest{
-        name: image_ref.to_string()
-    };
-    let req = with_namespace!(req, "k8s.io");
-    let resp = imageChannel.get(req).await?;
-
-    let image_digest = resp.into_inner().image.unwrap().target.unwrap().digest;
-
-    let content = get_content(&image_digest, &client).await?;
-    let is_image_manifest = content.get("layers") != None;
-
-    if is_image_manifest { // https://github.com/opencontainers/image-spec/blob/main/manifest.md
-        return Ok(content);
-    } 
-    // else content is an image index https://github.com/opencontainers/image-spec/blob/main/image-index.md
-    
-    let image_index = content;
-    let manifests = image_index["manifests"].as_array().unwrap();
-
-    let mut manifestAmd64 = &serde_json::Value::Null;
-
-    for entry in manifests {
-        let platform = entry["platform"].as_object().unwrap();
-        let architecture = platform["architecture"].as_str().unwrap();
-        let os = platform["os"].as_str().unwrap();
-        if architecture == "amd64" && os == "linux" {
-            manifestAmd64 = entry;
-            break;
-        }
-    }
-
-    let image_digest = manifestAmd64["digest"].as_str().unwrap();
-    
-    Ok(get_content(image_digest, &client).await?)
-}
-
 async fn get_image_layers(
     use_cached_files: bool,
+    cache_budget: &layer_cache::CacheBudget,
+    concurrency: usize,
+    image_ref: &str,
     manifest: &serde_json::Value,
     config_layer: &DockerConfigLayer,
-    client: &containerd_client::Client
+    source: Arc<dyn ImageSource>,
 ) -> Result<Vec<ImageLayer>> {
-    let mut layer_index = 0;
-    let mut layersVec = Vec::new();
-
-    let layers = manifest["layers"].as_array().unwrap();
-    
-    for layer in layers {
-        if layer["mediaType"].as_str().unwrap()
-        .eq("application/vnd.docker.image.rootfs.diff.tar.gzip") {
-            if layer_index < config_layer.rootfs.diff_ids.len() {
-                let imageLayer = ImageLayer {
-                    diff_id: config_layer.rootfs.diff_ids[layer_index].clone(),
-                    verity_hash: get_verity_hash(
-                        use_cached_files,
-                        layer["digest"].as_str().unwrap(),
-                        &client
-                    ).await?,
-                };
-                layersVec.push(imageLayer);
-            } else {
-                return Err(anyhow!("Too many Docker gzip layers"));
-            }
-            layer_index += 1;
+    let base_dir = std::path::Path::new("layers_cache");
+    let cache = Arc::new(Mutex::new(layer_cache::LayerCache::load(base_dir)));
+    let digest_locks: DigestLocks = Arc::new(Mutex::new(HashMap::new()));
+
+    let layers = manifest["layers"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Image manifest is missing a layers array"))?;
+
+    let mut pending_layers = Vec::new();
+    for (layer_index, layer) in layers.iter().enumerate() {
+        let media_type = layer["mediaType"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Image manifest layer {layer_index} is missing a mediaType"))?;
+        let compression = layer_compression(media_type)?;
+        if layer_index >= config_layer.rootfs.diff_ids.len() {
+            return Err(anyhow!("Too many layers in image manifest"));
         }
+        let digest = layer["digest"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Image manifest layer {layer_index} is missing a digest"))?
+            .to_string();
+        pending_layers.push((
+            config_layer.rootfs.diff_ids[layer_index].clone(),
+            digest,
+            compression,
+        ));
     }
 
-    Ok(layersVec)
+    // Download and hash up to `concurrency` layers at a time. `buffered`
+    // (rather than `buffer_unordered`) keeps the resulting layers in the
+    // same order as `pending_layers`, matching diff_ids.
+    let result: Result<Vec<ImageLayer>> = stream::iter(pending_layers.into_iter().map(
+        |(diff_id, digest, compression)| {
+            let image_ref = image_ref.to_string();
+            let cache_budget = *cache_budget;
+            let source = source.clone();
+            let cache = cache.clone();
+            let digest_locks = digest_locks.clone();
+            async move {
+                let verity_hash = get_verity_hash(
+                    use_cached_files,
+                    &cache_budget,
+                    cache,
+                    &digest_locks,
+                    &image_ref,
+                    &digest,
+                    compression,
+                    source,
+                )
+                .await?;
+                Ok::<ImageLayer, anyhow::Error>(ImageLayer {
+                    diff_id,
+                    verity_hash,
+                })
+            }
+        },
+    ))
+    .buffered(concurrency.max(1))
+    .try_collect()
+    .await;
+
+    // Only one task should ever tear down the whole shared directory, and
+    // only once every layer is done with it - doing this per-layer inside
+    // `get_verity_hash` raced concurrent tasks that were still reading
+    // their own files out of `base_dir`. A layer that fails panics inside
+    // `get_verity_hash`, so on that path the directory is left in place.
+    if !use_cached_files {
+        let _ = std::fs::remove_dir_all(base_dir);
+    }
+
+    result
 }
 
 fn delete_files(decompressed_path: &Path, compressed_path: &Path, verity_path: &Path) {
@@ -345,9 +369,20 @@ fn delete_files(decompressed_path: &Path, compressed_path: &Path, verity_path: &
 
 async fn get_verity_hash(
     use_cached_files: bool,
+    cache_budget: &layer_cache::CacheBudget,
+    cache: Arc<Mutex<layer_cache::LayerCache>>,
+    digest_locks: &DigestLocks,
+    image_ref: &str,
     layer_digest: &str,
-    client: &containerd_client::Client
+    compression: LayerCompression,
+    source: Arc<dyn ImageSource>,
 ) -> Result<String> {
+    // Hold this digest's lock for the rest of the function, so that a
+    // manifest repeating the same digest across layers never races two
+    // tasks over the same cache file names.
+    let lock = digest_lock(digest_locks, layer_digest).await;
+    let _guard = lock.lock().await;
+
     let base_dir = std::path::Path::new("layers_cache");
 
     // Use file names supported by both Linux and Windows.
@@ -357,7 +392,11 @@ async fn get_verity_hash(
     decompressed_path.set_extension("tar");
 
     let mut compressed_path = decompressed_path.clone();
-    compressed_path.set_extension("gz");
+    compressed_path.set_extension(match compression {
+        LayerCompression::Gzip => "gz",
+        LayerCompression::Zstd => "zst",
+        LayerCompression::None => "raw",
+    });
 
     let mut verity_path = decompressed_path.clone();
     verity_path.set_extension("verity");
@@ -370,12 +409,14 @@ async fn get_verity_hash(
         info!("Using cached file {:?}", &verity_path);
     } else if let Err(e) = create_verity_hash_file(
         use_cached_files,
+        image_ref,
         layer_digest,
+        compression,
         &base_dir,
         &decompressed_path,
         &compressed_path,
         &verity_path,
-        &client
+        source,
     )
     .await
     {
@@ -396,10 +437,20 @@ async fn get_verity_hash(
         }
     }
 
-    if !use_cached_files {
-        let _ = std::fs::remove_dir_all(&base_dir);
-    } else if error {
-        delete_files(&decompressed_path, &compressed_path, &verity_path);
+    // When not using the cache, the whole `base_dir` is torn down once by
+    // `get_image_layers` after every layer has finished, so there's
+    // nothing for this task to clean up itself.
+    if use_cached_files {
+        if error {
+            delete_files(&decompressed_path, &compressed_path, &verity_path);
+        } else {
+            let mut cache = cache.lock().await;
+            cache.touch(layer_digest);
+            let in_flight = in_flight_digests(digest_locks).await;
+            if let Err(e) = cache.enforce_budget(cache_budget, &in_flight) {
+                warn!("Failed to enforce layers_cache budget: {e}");
+            }
+        }
     }
 
     if error {
@@ -411,12 +462,14 @@ async fn get_verity_hash(
 
 async fn create_verity_hash_file(
     use_cached_files: bool,
+    image_ref: &str,
     layer_digest: &str,
+    compression: LayerCompression,
     base_dir: &Path,
     decompressed_path: &Path,
     compressed_path: &Path,
     verity_path: &Path,
-    client: &containerd_client::Client
+    source: Arc<dyn ImageSource>,
 ) -> Result<()> {
     if use_cached_files && decompressed_path.exists() {
         info!("Using cached file {:?}", &decompressed_path);
@@ -425,53 +478,44 @@ async fn create_verity_hash_file(
 
         create_decompressed_layer_file(
             use_cached_files,
+            image_ref,
             layer_digest,
+            compression,
             &decompressed_path,
             &compressed_path,
-            &client
+            source,
         )
         .await?;
     }
 
-    do_create_verity_hash_file(decompressed_path, verity_path)
+    // dm-verity hashing is CPU-bound, so run it on the blocking pool
+    // rather than tying up the async executor that's driving the other
+    // concurrent layer downloads.
+    let decompressed_path = decompressed_path.to_path_buf();
+    let verity_path = verity_path.to_path_buf();
+    tokio::task::spawn_blocking(move || do_create_verity_hash_file(&decompressed_path, &verity_path))
+        .await?
 }
 
 async fn create_decompressed_layer_file(
     use_cached_files: bool,
+    image_ref: &str,
     layer_digest: &str,
+    compression: LayerCompression,
     decompressed_path: &Path,
     compressed_path: &Path,
-    client: &containerd_client::Client
+    source: Arc<dyn ImageSource>,
 ) -> Result<()> {
     if use_cached_files && compressed_path.exists() {
         info!("Using cached file {:?}", &compressed_path);
     } else {
-        info!("Pulling layer {layer_digest}");
         let mut file = tokio::fs::File::create(&compressed_path)
             .await
             .map_err(|e| anyhow!(e)).expect("Failed to create file");
 
-        info!("Decompressing layer");
-    
-        let req = containerd_client::services::v1::ReadContentRequest {
-            digest: layer_digest.to_string(),
-            offset: 0,
-            size: 0,
-        };
-        let req = with_namespace!(req, "k8s.io");
-        let mut c = client.content();
-        let resp = c.read(req).await?;
-        let mut stream = resp.into_inner();
-    
-        while let Some(chunk) = stream.message().await? {
-            if chunk.offset < 0 {
-                print!("oop")
-            }
-            file.seek(io::SeekFrom::Start(chunk.offset as u64)).await?;
-            file.write_all(&chunk.data).await?;
-        }
-        
-        file.flush().await.map_err(|e| anyhow!(e)).expect("Failed to flush file");
+        info!("Downloading layer");
+
+        source.write_layer(image_ref, layer_digest, &mut file).await?;
     }
     let compressed_file = std::fs::File::open(&compressed_path).map_err(|e| anyhow!(e))?;
     let mut decompressed_file = std::fs::OpenOptions::new()
@@ -480,8 +524,22 @@ async fn create_decompressed_layer_file(
         .create(true)
         .truncate(true)
         .open(&decompressed_path)?;
-    let mut gz_decoder = flate2::read::GzDecoder::new(compressed_file);
-    std::io::copy(&mut gz_decoder, &mut decompressed_file).map_err(|e| anyhow!(e))?;
+
+    info!("Decompressing layer");
+    match compression {
+        LayerCompression::Gzip => {
+            let mut decoder = flate2::read::GzDecoder::new(compressed_file);
+            std::io::copy(&mut decoder, &mut decompressed_file).map_err(|e| anyhow!(e))?;
+        }
+        LayerCompression::Zstd => {
+            let mut decoder = zstd::stream::Decoder::new(compressed_file).map_err(|e| anyhow!(e))?;
+            std::io::copy(&mut decoder, &mut decompressed_file).map_err(|e| anyhow!(e))?;
+        }
+        LayerCompression::None => {
+            let mut reader = compressed_file;
+            std::io::copy(&mut reader, &mut decompressed_file).map_err(|e| anyhow!(e))?;
+        }
+    }
 
     info!("Adding tarfs index to layer");
     decompressed_file.seek(std::io::SeekFrom::Start(0))?;
@@ -513,6 +571,123 @@ fn do_create_verity_hash_file(path: &Path, verity_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Knobs passed to `Container::new_from_source` by [`get_container`].
+/// [`PullOptions::from_env`] reads the image source, layer concurrency and
+/// target platform from the environment instead of hardcoding them;
+/// `cache_budget` is not read from the environment and stays at
+/// [`CacheBudget::default`].
+pub struct PullOptions {
+    pub source_kind: ImageSourceKind,
+    pub cache_budget: CacheBudget,
+    pub target_platform: TargetPlatform,
+    pub concurrency: usize,
+}
+
+impl Default for PullOptions {
+    fn default() -> Self {
+        Self {
+            source_kind: ImageSourceKind::default(),
+            cache_budget: CacheBudget::default(),
+            target_platform: TargetPlatform::default(),
+            concurrency: LAYER_CONCURRENCY,
+        }
+    }
+}
+
+impl PullOptions {
+    /// Read overrides from the environment, the same way [`auth`] honors
+    /// `DOCKER_CONFIG`: `GENPOLICY_IMAGE_SOURCE` (`containerd` or
+    /// `distribution`), `GENPOLICY_LAYER_CONCURRENCY`, and
+    /// `GENPOLICY_TARGET_PLATFORM` (e.g. `arm64/linux/v8`). Any variable
+    /// that's unset or unparseable falls back to its default.
+    pub fn from_env() -> Self {
+        let mut options = Self::default();
+
+        if let Ok(source) = std::env::var("GENPOLICY_IMAGE_SOURCE") {
+            options.source_kind = match source.as_str() {
+                "distribution" => ImageSourceKind::Distribution,
+                _ => ImageSourceKind::Containerd,
+            };
+        }
+
+        if let Ok(concurrency) = std::env::var("GENPOLICY_LAYER_CONCURRENCY") {
+            if let Ok(concurrency) = concurrency.parse() {
+                options.concurrency = concurrency;
+            }
+        }
+
+        if let Ok(platform) = std::env::var("GENPOLICY_TARGET_PLATFORM") {
+            if let Some(target_platform) = parse_target_platform(&platform) {
+                options.target_platform = target_platform;
+            }
+        }
+
+        options
+    }
+}
+
+/// Parse a `architecture/os[/variant]` string, e.g. `arm64/linux/v8`.
+fn parse_target_platform(platform: &str) -> Option<TargetPlatform> {
+    let mut parts = platform.splitn(3, '/');
+    let architecture = parts.next()?.to_string();
+    let os = parts.next()?.to_string();
+    let variant = parts.next().map(str::to_string);
+    Some(TargetPlatform {
+        architecture,
+        os,
+        variant,
+    })
+}
+
 pub async fn get_container(use_cache: bool, image: &str) -> Result<Container> {
-    Container::new(use_cache, image).await
+    get_container_with_options(use_cache, image, PullOptions::from_env()).await
+}
+
+/// Like [`get_container`], but lets the caller pass [`PullOptions`]
+/// directly instead of reading them from the environment.
+pub async fn get_container_with_options(
+    use_cache: bool,
+    image: &str,
+    options: PullOptions,
+) -> Result<Container> {
+    Container::new_from_source(
+        options.source_kind,
+        use_cache,
+        options.cache_budget,
+        options.target_platform,
+        options.concurrency,
+        image,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_compression_dispatches_known_media_types() {
+        assert_eq!(
+            layer_compression("application/vnd.docker.image.rootfs.diff.tar.gzip").unwrap(),
+            LayerCompression::Gzip
+        );
+        assert_eq!(
+            layer_compression("application/vnd.oci.image.layer.v1.tar+gzip").unwrap(),
+            LayerCompression::Gzip
+        );
+        assert_eq!(
+            layer_compression("application/vnd.oci.image.layer.v1.tar+zstd").unwrap(),
+            LayerCompression::Zstd
+        );
+        assert_eq!(
+            layer_compression("application/vnd.oci.image.layer.v1.tar").unwrap(),
+            LayerCompression::None
+        );
+    }
+
+    #[test]
+    fn layer_compression_errors_on_unknown_media_type() {
+        let err = layer_compression("application/vnd.oci.image.layer.v1.tar+bzip2").unwrap_err();
+        assert!(err.to_string().contains("application/vnd.oci.image.layer.v1.tar+bzip2"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,140 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Registry credential resolution: `~/.docker/config.json` (honoring
+//! `DOCKER_CONFIG`), plain `auths` entries, and external credential
+//! helpers.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// A resolved username/password pair for a registry.
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Default)]
+struct DockerConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuthEntry>,
+
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct DockerAuthEntry {
+    auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CredentialHelperResponse {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+/// Resolve credentials for `registry` from the Docker config file, trying
+/// a literal `auths` entry first, then any configured credential helper
+/// (per-registry `credHelpers`, falling back to the global `credsStore`).
+pub fn lookup_credentials(registry: &str) -> Option<Credentials> {
+    let config = read_docker_config()?;
+
+    if let Some(auth) = config.auths.get(registry).and_then(|entry| entry.auth.as_ref()) {
+        if let Some(creds) = decode_basic_auth(auth) {
+            return Some(creds);
+        }
+    }
+
+    let helper = config
+        .cred_helpers
+        .get(registry)
+        .or(config.creds_store.as_ref())?;
+    run_credential_helper(helper, registry)
+}
+
+fn docker_config_path() -> PathBuf {
+    if let Ok(dir) = std::env::var("DOCKER_CONFIG") {
+        return PathBuf::from(dir).join("config.json");
+    }
+
+    let home = std::env::var("HOME").unwrap_or_default();
+    PathBuf::from(home).join(".docker").join("config.json")
+}
+
+fn read_docker_config() -> Option<DockerConfigFile> {
+    let data = std::fs::read_to_string(docker_config_path()).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+fn decode_basic_auth(auth: &str) -> Option<Credentials> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let decoded = general_purpose::STANDARD.decode(auth).ok()?;
+    let text = String::from_utf8(decoded).ok()?;
+    let (username, password) = text.split_once(':')?;
+    Some(Credentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Invoke `docker-credential-<helper> get`, passing `registry` on stdin,
+/// following the protocol described in
+/// <https://github.com/docker/docker-credential-helpers>.
+fn run_credential_helper(helper: &str, registry: &str) -> Option<Credentials> {
+    let mut child = Command::new(format!("docker-credential-{helper}"))
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    child.stdin.as_mut()?.write_all(registry.as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let parsed: CredentialHelperResponse = serde_json::from_slice(&output.stdout).ok()?;
+    Some(Credentials {
+        username: parsed.username,
+        password: parsed.secret,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_basic_auth_splits_username_and_password() {
+        // "alice:hunter2" base64-encoded.
+        let creds = decode_basic_auth("YWxpY2U6aHVudGVyMg==").unwrap();
+
+        assert_eq!(creds.username, "alice");
+        assert_eq!(creds.password, "hunter2");
+    }
+
+    #[test]
+    fn decode_basic_auth_rejects_malformed_input() {
+        assert!(decode_basic_auth("not-base64!!").is_none());
+    }
+
+    #[test]
+    fn decode_basic_auth_rejects_missing_separator() {
+        // "alicehunter2" base64-encoded, with no ':' once decoded.
+        assert!(decode_basic_auth("YWxpY2VodW50ZXIy").is_none());
+    }
+}
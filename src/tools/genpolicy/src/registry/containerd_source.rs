@@ -0,0 +1,192 @@
+// Copyright (c) 2023 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The original [`ImageSource`]: a running containerd daemon reached over
+//! its local socket.
+
+use super::auth;
+use super::image_source::{parse_image_reference, select_platform_entry, ImageSource, TargetPlatform};
+use super::DockerConfigLayer;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use containerd_client::services::v1::GetImageRequest;
+use containerd_client::with_namespace;
+use k8s_cri::v1::image_service_client::ImageServiceClient;
+use log::info;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use tokio::io;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tonic::transport::{Endpoint, Uri};
+use tower::service_fn;
+
+const CONTAINERD_SOCKET_PATH: &str = "npipe:////./pipe/containerd-containerd";
+
+/// [`ImageSource`] backed by a running containerd daemon, reached over
+/// [`CONTAINERD_SOCKET_PATH`].
+pub struct ContainerdSource {
+    client: containerd_client::Client,
+}
+
+impl ContainerdSource {
+    pub async fn new() -> Result<Self> {
+        let client = containerd_client::Client::from_path(CONTAINERD_SOCKET_PATH).await?;
+        Ok(Self { client })
+    }
+
+    async fn channel(&self) -> Result<tonic::transport::Channel> {
+        Ok(Endpoint::try_from("http://[::]")
+            .unwrap()
+            .connect_with_connector(service_fn(move |_: Uri| {
+                UnixStream::connect(CONTAINERD_SOCKET_PATH)
+            }))
+            .await?)
+    }
+
+    async fn get_content(&self, digest: &str) -> Result<serde_json::Value> {
+        let req = containerd_client::services::v1::ReadContentRequest {
+            digest: digest.to_string(),
+            offset: 0,
+            size: 0,
+        };
+        let req = with_namespace!(req, "k8s.io");
+        let mut c = self.client.content();
+        let resp = c.read(req).await?;
+        let mut stream = resp.into_inner();
+
+        while let Some(chunk) = stream.message().await? {
+            if chunk.offset < 0 {
+                return Err(anyhow!("Negative offset in chunk"));
+            } else {
+                return Ok(serde_json::from_slice(&chunk.data)?);
+            }
+        }
+
+        Err(anyhow!("Unable to find content for digest: {}", digest))
+    }
+}
+
+#[async_trait]
+impl ImageSource for ContainerdSource {
+    async fn pull_image(&self, image_ref: &str) -> Result<()> {
+        let channel = self.channel().await?;
+        let mut client = ImageServiceClient::new(channel);
+
+        let (registry, ..) = parse_image_reference(image_ref)?;
+        let auth = auth::lookup_credentials(&registry).map(|creds| k8s_cri::v1::AuthConfig {
+            username: creds.username,
+            password: creds.password,
+            ..Default::default()
+        });
+
+        let req = k8s_cri::v1::PullImageRequest {
+            image: Some(k8s_cri::v1::ImageSpec {
+                image: image_ref.to_string(),
+                annotations: HashMap::new(),
+            }),
+            auth,
+            sandbox_config: None,
+        };
+
+        client.pull_image(req).await?;
+
+        Ok(())
+    }
+
+    async fn image_manifest(
+        &self,
+        image_ref: &str,
+        target: &TargetPlatform,
+    ) -> Result<serde_json::Value> {
+        let mut image_channel = self.client.images();
+
+        let req = GetImageRequest {
+            name: image_ref.to_string(),
+        };
+        let req = with_namespace!(req, "k8s.io");
+        let resp = image_channel.get(req).await?;
+
+        let image_digest = resp.into_inner().image.unwrap().target.unwrap().digest;
+
+        let content = self.get_content(&image_digest).await?;
+        let is_image_manifest = content.get("layers").is_some();
+
+        if is_image_manifest {
+            // https://github.com/opencontainers/image-spec/blob/main/manifest.md
+            return Ok(content);
+        }
+
+        // else content is an image index
+        // https://github.com/opencontainers/image-spec/blob/main/image-index.md
+        let manifests = content["manifests"]
+            .as_array()
+            .ok_or_else(|| anyhow!("image index is missing a manifests array"))?;
+        let entry = select_platform_entry(manifests, target)?;
+        let image_digest = entry["digest"]
+            .as_str()
+            .ok_or_else(|| anyhow!("image index entry is missing a digest"))?;
+
+        self.get_content(image_digest).await
+    }
+
+    async fn config_layer(
+        &self,
+        image_ref: &str,
+        _manifest: &serde_json::Value,
+    ) -> Result<DockerConfigLayer> {
+        let channel = self.channel().await?;
+        let mut client = ImageServiceClient::new(channel);
+
+        let req = k8s_cri::v1::ImageStatusRequest {
+            image: Some(k8s_cri::v1::ImageSpec {
+                image: image_ref.to_string(),
+                annotations: HashMap::new(),
+            }),
+            verbose: true,
+        };
+
+        let resp = client.image_status(req).await?;
+        let image_layers = resp.into_inner();
+
+        let status_info: serde_json::Value =
+            serde_json::from_str(image_layers.info.get("info").unwrap())?;
+        let image_spec = status_info["imageSpec"]
+            .as_object()
+            .ok_or_else(|| anyhow!("image status response is missing an imageSpec object"))?;
+
+        Ok(serde_json::from_value(serde_json::to_value(image_spec)?)?)
+    }
+
+    async fn write_layer(
+        &self,
+        _image_ref: &str,
+        layer_digest: &str,
+        file: &mut tokio::fs::File,
+    ) -> Result<()> {
+        info!("Pulling layer {layer_digest}");
+
+        let req = containerd_client::services::v1::ReadContentRequest {
+            digest: layer_digest.to_string(),
+            offset: 0,
+            size: 0,
+        };
+        let req = with_namespace!(req, "k8s.io");
+        let mut c = self.client.content();
+        let resp = c.read(req).await?;
+        let mut stream = resp.into_inner();
+
+        while let Some(chunk) = stream.message().await? {
+            if chunk.offset < 0 {
+                return Err(anyhow!("Negative offset in chunk"));
+            }
+            file.seek(io::SeekFrom::Start(chunk.offset as u64)).await?;
+            file.write_all(&chunk.data).await?;
+        }
+
+        file.flush().await.map_err(|e| anyhow!(e))
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! An [`ImageSource`] that talks the OCI Distribution (Registry v2) HTTP
+//! API directly, so policy can be generated on a build host with no
+//! containerd daemon.
+
+use super::auth;
+use super::image_source::{parse_image_reference, select_platform_entry, ImageSource, TargetPlatform};
+use super::DockerConfigLayer;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::info;
+use std::collections::HashMap;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+     application/vnd.docker.distribution.manifest.list.v2+json, \
+     application/vnd.oci.image.manifest.v1+json, \
+     application/vnd.docker.distribution.manifest.v2+json";
+
+/// [`ImageSource`] backed by direct OCI Distribution HTTP requests against
+/// the image's registry.
+pub struct DistributionSource {
+    http: reqwest::Client,
+
+    /// Bearer tokens obtained from the Distribution token handshake,
+    /// cached per `"<registry>|<scope>"` for the lifetime of the pull.
+    /// `Mutex`-guarded, not `&mut self`, so concurrent per-layer tasks can
+    /// share one `DistributionSource` through an `Arc`.
+    bearer_tokens: Mutex<HashMap<String, String>>,
+}
+
+impl DistributionSource {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::builder().build()?,
+            bearer_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// `GET url`, attaching a cached bearer token if one is held for
+    /// `registry`/`repository`, and performing the Distribution token
+    /// handshake on a `401` before retrying once.
+    async fn get_authorized(
+        &self,
+        registry: &str,
+        repository: &str,
+        url: &str,
+        accept: Option<&str>,
+    ) -> Result<reqwest::Response> {
+        let scope = format!("repository:{repository}:pull");
+        let cache_key = format!("{registry}|{scope}");
+
+        let build = |token: Option<&str>, http: &reqwest::Client| {
+            let mut req = http.get(url);
+            if let Some(accept) = accept {
+                req = req.header(reqwest::header::ACCEPT, accept);
+            }
+            if let Some(token) = token {
+                req = req.bearer_auth(token);
+            }
+            req
+        };
+
+        let cached = self.bearer_tokens.lock().await.get(&cache_key).cloned();
+        let resp = build(cached.as_deref(), &self.http).send().await?;
+        if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+
+        let challenge = resp
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .ok_or_else(|| anyhow!("{url}: 401 response with no WWW-Authenticate challenge"))?
+            .to_str()?
+            .to_string();
+
+        let token = self.fetch_bearer_token(registry, &challenge, &scope).await?;
+        let resp = build(Some(&token), &self.http).send().await?;
+        self.bearer_tokens.lock().await.insert(cache_key, token);
+        Ok(resp)
+    }
+
+    /// Perform the Distribution token handshake described by a
+    /// `WWW-Authenticate: Bearer ...` challenge, returning the token to
+    /// retry the original request with.
+    async fn fetch_bearer_token(
+        &self,
+        registry: &str,
+        challenge: &str,
+        default_scope: &str,
+    ) -> Result<String> {
+        let params = parse_bearer_challenge(challenge)?;
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| anyhow!("Bearer challenge is missing realm: {challenge}"))?;
+        let service = params.get("service").cloned().unwrap_or_default();
+        let scope = params
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| default_scope.to_string());
+
+        let mut req = self.http.get(realm);
+        if !service.is_empty() {
+            req = req.query(&[("service", service.as_str())]);
+        }
+        if !scope.is_empty() {
+            req = req.query(&[("scope", scope.as_str())]);
+        }
+        if let Some(creds) = auth::lookup_credentials(registry) {
+            req = req.basic_auth(creds.username, Some(creds.password));
+        }
+
+        let resp = req.send().await?;
+        if resp.status() != reqwest::StatusCode::OK {
+            return Err(anyhow!(
+                "token request to {realm} returned status {}",
+                resp.status()
+            ));
+        }
+
+        let body: serde_json::Value = resp.json().await?;
+        body.get("token")
+            .or_else(|| body.get("access_token"))
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("token response from {realm} is missing a token"))
+    }
+
+    async fn get_manifest_by_reference(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> Result<serde_json::Value> {
+        let url = format!("https://{registry}/v2/{repository}/manifests/{reference}");
+        let resp = self
+            .get_authorized(registry, repository, &url, Some(MANIFEST_ACCEPT))
+            .await?;
+
+        if resp.status() != reqwest::StatusCode::OK {
+            return Err(anyhow!("GET {url} returned status {}", resp.status()));
+        }
+
+        Ok(resp.json().await?)
+    }
+
+    async fn get_blob(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+    ) -> Result<reqwest::Response> {
+        let url = format!("https://{registry}/v2/{repository}/blobs/{digest}");
+        let resp = self.get_authorized(registry, repository, &url, None).await?;
+
+        if resp.status() != reqwest::StatusCode::OK {
+            return Err(anyhow!("GET {url} returned status {}", resp.status()));
+        }
+
+        Ok(resp)
+    }
+}
+
+#[async_trait]
+impl ImageSource for DistributionSource {
+    /// The Distribution API has no separate pull step: every manifest/blob
+    /// request resolves and fetches in one round-trip.
+    async fn pull_image(&self, _image_ref: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn image_manifest(
+        &self,
+        image_ref: &str,
+        target: &TargetPlatform,
+    ) -> Result<serde_json::Value> {
+        let (registry, repository, reference) = parse_image_reference(image_ref)?;
+        info!("Resolving manifest for {repository}:{reference} from {registry}");
+
+        let content = self
+            .get_manifest_by_reference(&registry, &repository, &reference)
+            .await?;
+
+        if content.get("layers").is_some() {
+            // https://github.com/opencontainers/image-spec/blob/main/manifest.md
+            return Ok(content);
+        }
+
+        // else content is an image index
+        // https://github.com/opencontainers/image-spec/blob/main/image-index.md
+        let manifests = content["manifests"]
+            .as_array()
+            .ok_or_else(|| anyhow!("image index is missing a manifests array"))?;
+        let entry = select_platform_entry(manifests, target)?;
+        let digest = entry["digest"]
+            .as_str()
+            .ok_or_else(|| anyhow!("image index entry is missing a digest"))?
+            .to_string();
+
+        self.get_manifest_by_reference(&registry, &repository, &digest)
+            .await
+    }
+
+    async fn config_layer(
+        &self,
+        image_ref: &str,
+        manifest: &serde_json::Value,
+    ) -> Result<DockerConfigLayer> {
+        let (registry, repository, _reference) = parse_image_reference(image_ref)?;
+
+        let config_digest = manifest["config"]["digest"]
+            .as_str()
+            .ok_or_else(|| anyhow!("manifest is missing a config digest"))?;
+
+        let resp = self.get_blob(&registry, &repository, config_digest).await?;
+        Ok(resp.json().await?)
+    }
+
+    async fn write_layer(
+        &self,
+        image_ref: &str,
+        layer_digest: &str,
+        file: &mut tokio::fs::File,
+    ) -> Result<()> {
+        let (registry, repository, _reference) = parse_image_reference(image_ref)?;
+        info!("Pulling layer {layer_digest}");
+
+        let resp = self.get_blob(&registry, &repository, layer_digest).await?;
+        let mut stream = resp.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+
+        file.flush().await.map_err(|e| anyhow!(e))
+    }
+}
+
+/// Parse a `WWW-Authenticate: Bearer realm="...",service="...",scope="..."`
+/// challenge into its key/value parameters.
+fn parse_bearer_challenge(challenge: &str) -> Result<HashMap<String, String>> {
+    let rest = challenge
+        .trim()
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| anyhow!("unsupported WWW-Authenticate challenge: {challenge}"))?;
+
+    let mut params = HashMap::new();
+    for part in rest.split(',') {
+        if let Some((key, value)) = part.trim().split_once('=') {
+            params.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string());
+        }
+    }
+
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bearer_challenge_reads_all_parameters() {
+        let challenge = r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/busybox:pull""#;
+
+        let params = parse_bearer_challenge(challenge).unwrap();
+
+        assert_eq!(params.get("realm").unwrap(), "https://auth.docker.io/token");
+        assert_eq!(params.get("service").unwrap(), "registry.docker.io");
+        assert_eq!(params.get("scope").unwrap(), "repository:library/busybox:pull");
+    }
+
+    #[test]
+    fn parse_bearer_challenge_rejects_non_bearer_schemes() {
+        assert!(parse_bearer_challenge(r#"Basic realm="registry""#).is_err());
+    }
+}
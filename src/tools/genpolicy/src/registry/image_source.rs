@@ -0,0 +1,266 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Pluggable backends for resolving an image reference into a manifest,
+//! config layer, and layer blobs.
+//!
+//! `Container::new` only depends on the [`ImageSource`] trait, never on a
+//! specific way of reaching the registry. This lets the containerd-backed
+//! implementation below be swapped for a direct OCI Distribution HTTP
+//! client on build hosts that have no containerd daemon.
+
+use super::DockerConfigLayer;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// A backend capable of resolving an image reference into the pieces
+/// `Container::new` needs: a manifest, a parsed config layer, and the
+/// compressed bytes of each layer.
+///
+/// Methods take `&self`, not `&mut self`: both the containerd and
+/// Distribution backends wrap an already-`Sync` client, so an
+/// `Arc<dyn ImageSource>` can be shared across the concurrent per-layer
+/// tasks `Container::new` fans out.
+#[async_trait]
+pub trait ImageSource: Send + Sync {
+    /// Make sure `image_ref` is available to this source, pulling it if
+    /// the backend requires an explicit pull step.
+    async fn pull_image(&self, image_ref: &str) -> Result<()>;
+
+    /// Resolve `image_ref` to the image manifest matching `target`,
+    /// following an image index/manifest-list if necessary.
+    async fn image_manifest(
+        &self,
+        image_ref: &str,
+        target: &TargetPlatform,
+    ) -> Result<serde_json::Value>;
+
+    /// Fetch and parse the config layer referenced by `manifest`, the
+    /// platform-resolved manifest `image_manifest` already returned -
+    /// passed back in rather than re-resolved, so callers that already
+    /// paid for that resolution don't pay for it again.
+    async fn config_layer(
+        &self,
+        image_ref: &str,
+        manifest: &serde_json::Value,
+    ) -> Result<DockerConfigLayer>;
+
+    /// Write the compressed bytes of the layer identified by
+    /// `layer_digest`, belonging to `image_ref`, into `file`.
+    async fn write_layer(
+        &self,
+        image_ref: &str,
+        layer_digest: &str,
+        file: &mut tokio::fs::File,
+    ) -> Result<()>;
+}
+
+/// Which [`ImageSource`] backend `Container::new` should construct.
+///
+/// Defaults to [`ImageSourceKind::Containerd`] so that callers which don't
+/// pass an explicit choice keep pulling through the containerd daemon
+/// exactly as before.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageSourceKind {
+    /// Pull through a running containerd daemon (the original backend).
+    #[default]
+    Containerd,
+
+    /// Talk the OCI Distribution (Registry v2) HTTP API directly, so
+    /// policy can be generated on a build host with no containerd.
+    Distribution,
+}
+
+/// The architecture/os/variant an image index entry should be pulled for,
+/// in the same terms as the OCI image-index `platform` object (e.g.
+/// `arm64/linux/v8`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TargetPlatform {
+    pub architecture: String,
+    pub os: String,
+    pub variant: Option<String>,
+}
+
+impl TargetPlatform {
+    /// Build a [`TargetPlatform`] describing the host this process is
+    /// running on, translating Rust's `std::env::consts` names to the
+    /// equivalent OCI platform names (e.g. `x86_64` -> `amd64`).
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "x86" => "386",
+            other => other,
+        }
+        .to_string();
+
+        let os = match std::env::consts::OS {
+            "macos" => "darwin",
+            other => other,
+        }
+        .to_string();
+
+        // The only architecture with OCI-defined variants in common use.
+        let variant = (architecture == "arm64").then(|| "v8".to_string());
+
+        Self {
+            architecture,
+            os,
+            variant,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.variant {
+            Some(variant) => format!("{}/{}/{variant}", self.architecture, self.os),
+            None => format!("{}/{}", self.architecture, self.os),
+        }
+    }
+}
+
+impl Default for TargetPlatform {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+/// Select the manifest entry matching `target` out of an image index or
+/// manifest list, shared by every [`ImageSource`] backend so the
+/// platform-selection rule stays identical across them.
+pub(crate) fn select_platform_entry<'a>(
+    manifests: &'a [serde_json::Value],
+    target: &TargetPlatform,
+) -> Result<&'a serde_json::Value> {
+    let mut available = Vec::new();
+
+    for entry in manifests {
+        let platform = entry
+            .get("platform")
+            .and_then(serde_json::Value::as_object)
+            .ok_or_else(|| anyhow::anyhow!("image index entry is missing a platform object"))?;
+        let entry_arch = platform.get("architecture").and_then(serde_json::Value::as_str);
+        let entry_os = platform.get("os").and_then(serde_json::Value::as_str);
+        let entry_variant = platform.get("variant").and_then(serde_json::Value::as_str);
+
+        if entry_arch == Some(target.architecture.as_str())
+            && entry_os == Some(target.os.as_str())
+            && (target.variant.is_none() || entry_variant == target.variant.as_deref())
+        {
+            return Ok(entry);
+        }
+
+        available.push(match entry_variant {
+            Some(variant) => format!("{}/{}/{variant}", entry_arch.unwrap_or("?"), entry_os.unwrap_or("?")),
+            None => format!("{}/{}", entry_arch.unwrap_or("?"), entry_os.unwrap_or("?")),
+        });
+    }
+
+    Err(anyhow::anyhow!(
+        "no {} entry in image index; available platforms: {}",
+        target.describe(),
+        available.join(", ")
+    ))
+}
+
+/// Default registry host for Docker Hub image references that don't name
+/// one explicitly (e.g. `busybox:latest` or `library/busybox:latest`).
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+
+/// Split an image reference into `(registry, repository, reference)`,
+/// where `reference` is a tag or a `sha256:...` digest.
+///
+/// Mirrors the conventions `docker pull` uses: a reference with no
+/// registry component defaults to Docker Hub, and a Docker Hub reference
+/// with no namespace defaults to the `library/` namespace. Shared by every
+/// backend that needs a registry host to resolve credentials against, not
+/// just [`super::distribution_source::DistributionSource`].
+pub(crate) fn parse_image_reference(image_ref: &str) -> Result<(String, String, String)> {
+    let (name, reference) = match image_ref.rsplit_once('@') {
+        Some((name, digest)) => (name, digest.to_string()),
+        None => match image_ref.rsplit_once(':') {
+            // Don't confuse a registry port (e.g. "host:5000/repo") with a tag.
+            Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+            _ => (image_ref, "latest".to_string()),
+        },
+    };
+
+    let (registry, repository) = match name.split_once('/') {
+        Some((first, rest)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+            (first.to_string(), rest.to_string())
+        }
+        Some(_) => (DOCKER_HUB_REGISTRY.to_string(), name.to_string()),
+        None => (
+            DOCKER_HUB_REGISTRY.to_string(),
+            format!("library/{name}"),
+        ),
+    };
+
+    Ok((registry, repository, reference))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn platform(architecture: &str, os: &str, variant: Option<&str>) -> TargetPlatform {
+        TargetPlatform {
+            architecture: architecture.to_string(),
+            os: os.to_string(),
+            variant: variant.map(str::to_string),
+        }
+    }
+
+    fn index_entry(architecture: &str, os: &str, variant: Option<&str>) -> serde_json::Value {
+        let mut platform = json!({"architecture": architecture, "os": os});
+        if let Some(variant) = variant {
+            platform["variant"] = json!(variant);
+        }
+        json!({"digest": format!("sha256:{architecture}-{os}"), "platform": platform})
+    }
+
+    #[test]
+    fn select_platform_entry_matches_architecture_and_os() {
+        let manifests = vec![index_entry("arm64", "linux", Some("v8")), index_entry("amd64", "linux", None)];
+        let target = platform("amd64", "linux", None);
+
+        let entry = select_platform_entry(&manifests, &target).unwrap();
+
+        assert_eq!(entry["digest"], "sha256:amd64-linux");
+    }
+
+    #[test]
+    fn select_platform_entry_requires_matching_variant() {
+        let manifests = vec![index_entry("arm64", "linux", Some("v7"))];
+        let target = platform("arm64", "linux", Some("v8"));
+
+        let err = select_platform_entry(&manifests, &target).unwrap_err();
+
+        assert!(err.to_string().contains("arm64/linux/v8"));
+    }
+
+    #[test]
+    fn select_platform_entry_ignores_variant_when_target_has_none() {
+        let manifests = vec![index_entry("arm64", "linux", Some("v8"))];
+        let target = platform("arm64", "linux", None);
+
+        let entry = select_platform_entry(&manifests, &target).unwrap();
+
+        assert_eq!(entry["digest"], "sha256:arm64-linux");
+    }
+
+    #[test]
+    fn select_platform_entry_errors_listing_available_platforms_when_nothing_matches() {
+        let manifests = vec![index_entry("arm64", "linux", Some("v8")), index_entry("amd64", "windows", None)];
+        let target = platform("amd64", "linux", None);
+
+        let err = select_platform_entry(&manifests, &target).unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("arm64/linux/v8"));
+        assert!(message.contains("amd64/windows"));
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright (c) 2024 Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A size- and count-bounded on-disk cache for downloaded layers, so
+//! `layers_cache` doesn't grow without limit when `use_cached_files` keeps
+//! reusing it across runs.
+//!
+//! Entries are tracked least-recently-used in a small sidecar index file
+//! next to the cached `.tar`/`.gz`/`.verity` triples. The `.verity` file is
+//! the valuable artifact (a few dozen bytes), so eviction always drops the
+//! large decompressed `.tar`/`.gz` intermediates of the oldest entries
+//! first, and only removes `.verity` files - dropping the entry entirely -
+//! if the budget is still exceeded afterwards.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// Limits enforced by [`LayerCache::enforce_budget`].
+#[derive(Clone, Copy, Debug)]
+pub struct CacheBudget {
+    /// Maximum total bytes occupied by cached `.tar`/`.gz`/`.zst`/`.raw`/`.verity` files.
+    pub max_bytes: u64,
+
+    /// Maximum number of distinct layer digests kept in the cache.
+    pub max_entries: usize,
+}
+
+impl Default for CacheBudget {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024 * 1024,
+            max_entries: 256,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    digest: String,
+    last_used_secs: u64,
+}
+
+/// An LRU index over the digests cached under a `layers_cache` directory.
+pub struct LayerCache {
+    base_dir: PathBuf,
+    entries: Vec<CacheEntry>,
+}
+
+impl LayerCache {
+    /// Load the sidecar index from `base_dir`, or start an empty one if
+    /// it doesn't exist yet.
+    pub fn load(base_dir: &Path) -> Self {
+        let entries = std::fs::read_to_string(base_dir.join(INDEX_FILE_NAME))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+
+        Self {
+            base_dir: base_dir.to_path_buf(),
+            entries,
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.base_dir)?;
+        let data = serde_json::to_string(&self.entries)?;
+        std::fs::write(self.base_dir.join(INDEX_FILE_NAME), data)?;
+        Ok(())
+    }
+
+    /// Record that `digest` was just used (created or read from cache).
+    pub fn touch(&mut self, digest: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.digest == digest) {
+            entry.last_used_secs = now;
+        } else {
+            self.entries.push(CacheEntry {
+                digest: digest.to_string(),
+                last_used_secs: now,
+            });
+        }
+    }
+
+    fn file_name(digest: &str) -> String {
+        str::replace(digest, ":", "-")
+    }
+
+    fn tar_path(&self, digest: &str) -> PathBuf {
+        self.base_dir.join(Self::file_name(digest)).with_extension("tar")
+    }
+
+    fn gz_path(&self, digest: &str) -> PathBuf {
+        self.base_dir.join(Self::file_name(digest)).with_extension("gz")
+    }
+
+    fn zst_path(&self, digest: &str) -> PathBuf {
+        self.base_dir.join(Self::file_name(digest)).with_extension("zst")
+    }
+
+    fn raw_path(&self, digest: &str) -> PathBuf {
+        self.base_dir.join(Self::file_name(digest)).with_extension("raw")
+    }
+
+    fn verity_path(&self, digest: &str) -> PathBuf {
+        self.base_dir.join(Self::file_name(digest)).with_extension("verity")
+    }
+
+    fn entry_bytes(&self, digest: &str) -> u64 {
+        [
+            self.tar_path(digest),
+            self.gz_path(digest),
+            self.zst_path(digest),
+            self.raw_path(digest),
+            self.verity_path(digest),
+        ]
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|e| self.entry_bytes(&e.digest)).sum()
+    }
+
+    fn over_budget(&self, budget: &CacheBudget) -> bool {
+        self.entries.len() > budget.max_entries || self.total_bytes() > budget.max_bytes
+    }
+
+    /// Evict least-recently-used entries until `budget` is satisfied,
+    /// dropping `.tar`/`.gz` intermediates before `.verity` files, and
+    /// persist the updated index. Digests in `in_flight` are left alone
+    /// even if they're the oldest entries, since another task is still
+    /// downloading or reading their cache files; deleting those out from
+    /// under it would corrupt its read.
+    pub fn enforce_budget(&mut self, budget: &CacheBudget, in_flight: &HashSet<String>) -> Result<()> {
+        self.entries.sort_by_key(|e| e.last_used_secs);
+
+        // Pass 1: drop the decompressed/compressed intermediates of the
+        // oldest entries first; keep their `.verity` hash around.
+        for entry in &self.entries {
+            if !self.over_budget(budget) {
+                break;
+            }
+            if in_flight.contains(&entry.digest) {
+                continue;
+            }
+            let _ = std::fs::remove_file(self.tar_path(&entry.digest));
+            let _ = std::fs::remove_file(self.gz_path(&entry.digest));
+            let _ = std::fs::remove_file(self.zst_path(&entry.digest));
+            let _ = std::fs::remove_file(self.raw_path(&entry.digest));
+        }
+
+        // Pass 2: still over budget? Drop the `.verity` file too, removing
+        // the entry from the cache entirely, oldest first.
+        let mut evicted_digests = Vec::new();
+        for entry in &self.entries {
+            let remaining = self.entries.len() - evicted_digests.len();
+            if remaining <= budget.max_entries && self.total_bytes() <= budget.max_bytes {
+                break;
+            }
+            if in_flight.contains(&entry.digest) {
+                continue;
+            }
+            let _ = std::fs::remove_file(self.tar_path(&entry.digest));
+            let _ = std::fs::remove_file(self.gz_path(&entry.digest));
+            let _ = std::fs::remove_file(self.zst_path(&entry.digest));
+            let _ = std::fs::remove_file(self.raw_path(&entry.digest));
+            let _ = std::fs::remove_file(self.verity_path(&entry.digest));
+            evicted_digests.push(entry.digest.clone());
+        }
+        self.entries.retain(|e| !evicted_digests.contains(&e.digest));
+
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory under the OS temp dir, removed on drop, so each
+    /// test exercises `enforce_budget`'s real file-deletion logic without
+    /// tests stepping on each other's files.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "genpolicy-layer-cache-test-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn cache_with_entry(dir: &Path, digest: &str, last_used_secs: u64, tar_bytes: usize) -> LayerCache {
+        write_entry_files(dir, digest, tar_bytes, 4);
+
+        LayerCache {
+            base_dir: dir.to_path_buf(),
+            entries: vec![CacheEntry {
+                digest: digest.to_string(),
+                last_used_secs,
+            }],
+        }
+    }
+
+    fn write_entry_files(dir: &Path, digest: &str, tar_bytes: usize, verity_bytes: usize) {
+        let file_name = LayerCache::file_name(digest);
+        std::fs::write(dir.join(&file_name).with_extension("tar"), vec![0u8; tar_bytes]).unwrap();
+        std::fs::write(dir.join(&file_name).with_extension("verity"), vec![0u8; verity_bytes]).unwrap();
+    }
+
+    #[test]
+    fn enforce_budget_drops_intermediates_before_verity_files() {
+        let scratch = ScratchDir::new("intermediates-first");
+        let mut cache = cache_with_entry(&scratch.0, "sha256:old", 1, 1024);
+
+        // Below the tar+verity total, but above the verity file alone.
+        let budget = CacheBudget {
+            max_bytes: 10,
+            max_entries: 256,
+        };
+        cache.enforce_budget(&budget, &HashSet::new()).unwrap();
+
+        assert!(!cache.tar_path("sha256:old").exists());
+        assert!(cache.verity_path("sha256:old").exists());
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn enforce_budget_evicts_the_entry_once_dropping_intermediates_is_not_enough() {
+        let scratch = ScratchDir::new("evict-entry");
+        let mut cache = cache_with_entry(&scratch.0, "sha256:old", 1, 0);
+
+        let budget = CacheBudget {
+            max_bytes: 0,
+            max_entries: 256,
+        };
+        cache.enforce_budget(&budget, &HashSet::new()).unwrap();
+
+        assert!(!cache.verity_path("sha256:old").exists());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn enforce_budget_skips_in_flight_digests() {
+        let scratch = ScratchDir::new("in-flight");
+        let mut cache = cache_with_entry(&scratch.0, "sha256:busy", 1, 1024);
+
+        let budget = CacheBudget {
+            max_bytes: 0,
+            max_entries: 256,
+        };
+        let in_flight: HashSet<String> = [String::from("sha256:busy")].into_iter().collect();
+        cache.enforce_budget(&budget, &in_flight).unwrap();
+
+        assert!(cache.tar_path("sha256:busy").exists());
+        assert!(cache.verity_path("sha256:busy").exists());
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn enforce_budget_prefers_evicting_the_oldest_entry() {
+        let scratch = ScratchDir::new("oldest-first");
+        write_entry_files(&scratch.0, "sha256:old", 0, 1000);
+        write_entry_files(&scratch.0, "sha256:new", 0, 1000);
+        let mut cache = LayerCache {
+            base_dir: scratch.0.clone(),
+            entries: vec![
+                CacheEntry {
+                    digest: "sha256:old".to_string(),
+                    last_used_secs: 1,
+                },
+                CacheEntry {
+                    digest: "sha256:new".to_string(),
+                    last_used_secs: 2,
+                },
+            ],
+        };
+
+        // Enough budget for one entry's `.verity` file but not both: the
+        // older entry should be the one removed.
+        let budget = CacheBudget {
+            max_bytes: 1500,
+            max_entries: 256,
+        };
+        cache.enforce_budget(&budget, &HashSet::new()).unwrap();
+
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.entries[0].digest, "sha256:new");
+        assert!(!cache.verity_path("sha256:old").exists());
+        assert!(cache.verity_path("sha256:new").exists());
+    }
+}